@@ -11,8 +11,17 @@ import argparse
 import sys
 from pathlib import Path
 
-from src.entry import entry_point
-from src.logger import logger
+from src.entry import (
+    entry_point,
+    lint_templates,
+    score_results_file,
+    set_threads_count,
+    watch_input_dirs,
+)
+from src.logger import logger, redirect_logs_to_stderr, set_log_level
+from src.processors.manager import PROCESSOR_MANAGER
+from src.schemas import get_schema_json
+from src.self_test import run_self_test
 
 
 def parse_args():
@@ -69,6 +78,123 @@ def parse_args():
         run again until the template is set.",
     )
 
+    argparser.add_argument(
+        "-t",
+        "--markThreshold",
+        required=False,
+        type=int,
+        dest="markThreshold",
+        help="Override the fallback intensity threshold (0-255) below which a \
+        bubble is considered marked. Increase it for faint pencil marks.",
+    )
+
+    argparser.add_argument(
+        "--dryRun",
+        required=False,
+        dest="dryRun",
+        action="store_true",
+        help="Load and validate the templates and count the images that would \
+        be processed, without processing them.",
+    )
+
+    argparser.add_argument(
+        "--format",
+        required=False,
+        choices=["files", "ndjson", "both"],
+        dest="format",
+        help="Write the results to files (default), stream them to stdout as \
+        newline-delimited JSON, or both.",
+    )
+
+    argparser.add_argument(
+        "--watch",
+        required=False,
+        nargs="?",
+        const=2.0,
+        type=float,
+        dest="watch",
+        help="Keep watching the input directories and process new images as \
+        they appear, polling every WATCH seconds (default 2).",
+    )
+
+    argparser.add_argument(
+        "--resume",
+        required=False,
+        dest="resume",
+        action="store_true",
+        help="Continue an interrupted run in the same output directory, skipping \
+        the images it had already processed.",
+    )
+
+    argparser.add_argument(
+        "-j",
+        "--threads",
+        required=False,
+        type=int,
+        dest="threads",
+        help="Limit the number of threads used for image processing, 0 uses \
+        all the cores (default).",
+    )
+
+    argparser.add_argument(
+        "--selfTest",
+        required=False,
+        dest="selfTest",
+        action="store_true",
+        help="Detect a generated sheet with known marks to check that the \
+        installation works, then exit.",
+    )
+
+    argparser.add_argument(
+        "--logLevel",
+        required=False,
+        choices=["debug", "info", "warning", "error"],
+        dest="logLevel",
+        help="Only show the log messages of this level or above (default: info).",
+    )
+
+    argparser.add_argument(
+        "--scoreOnly",
+        required=False,
+        dest="scoreOnly",
+        help="Score the responses of a results csv from an earlier run against \
+        the evaluation.json of the input directory, without processing images.",
+    )
+
+    argparser.add_argument(
+        "--answerKeyDir",
+        required=False,
+        dest="answerKeyDir",
+        help="Directory of answer keys named by exam version (A.json, B.json, \
+        ...), each in the evaluation.json format. Every sheet is scored with \
+        the key of its bubbled 'version' field.",
+    )
+
+    argparser.add_argument(
+        "--processorsDir",
+        required=False,
+        dest="processorsDir",
+        help="Directory of python files with custom ImagePreprocessor classes, \
+        usable by their class name in the template preProcessors.",
+    )
+
+    argparser.add_argument(
+        "--emitSchema",
+        required=False,
+        dest="emitSchema",
+        action="store_true",
+        help="Print the JSON Schema of the template.json format, then exit.",
+    )
+
+    argparser.add_argument(
+        "--lintTemplate",
+        required=False,
+        dest="lintTemplate",
+        action="store_true",
+        help="Report overlapping bubbles, duplicate bubble values and other \
+        layout mistakes of the templates in the input directories, then exit.",
+    )
+
     (
         args,
         unknown,
@@ -87,6 +213,31 @@ def entry_point_for_args(args):
     if args["debug"] is True:
         # Disable tracebacks
         sys.tracebacklimit = 0
+    if args.get("format") in ["ndjson", "both"]:
+        redirect_logs_to_stderr()
+    if args.get("logLevel") is not None:
+        set_log_level(args["logLevel"])
+    set_threads_count(args.get("threads"))
+    if args.get("processorsDir") is not None:
+        PROCESSOR_MANAGER.load_processors_from_dir(Path(args["processorsDir"]))
+    if args.get("emitSchema"):
+        print(get_schema_json("template"))
+        return
+    if args.get("selfTest"):
+        sys.exit(0 if run_self_test() else 1)
+    if args.get("lintTemplate"):
+        warnings_count = sum(
+            lint_templates(Path(root)) for root in args["input_paths"]
+        )
+        sys.exit(0 if warnings_count == 0 else 1)
+    if args.get("scoreOnly") is not None:
+        score_results_file(
+            Path(args["scoreOnly"]), Path(args["input_paths"][0]), args
+        )
+        return
+    if args.get("watch") is not None:
+        watch_input_dirs([Path(root) for root in args["input_paths"]], args)
+        return
     for root in args["input_paths"]:
         entry_point(
             Path(root),
@@ -1,12 +1,15 @@
 # streamlit_grader_cropped_numeric.py
 import streamlit as st
-from PIL import Image, ImageEnhance
+from PIL import Image, ImageEnhance, ImageOps
 import numpy as np
 import pandas as pd
 import pytesseract
 import platform
 import os
 import math
+import io
+from upload_guard import UploadRejected, check_request_size, check_upload, extract_zip_images, sanitize_filename
+from results_cleanup import start_cleanup_thread
 
 # ---- Tesseract path ----
 if platform.system() == "Windows":
@@ -65,7 +68,8 @@ def save_results_csv(info, answers, filename_prefix="out", output_dir="results")
     os.makedirs(output_dir, exist_ok=True)
     student_id = info.get("Student ID") or "unknown"
     subject_id = info.get("Subject Code") or "subj"
-    csv_name = f"{student_id}_{subject_id}_{filename_prefix}.csv"
+    # OCR text and upload names must not be able to point outside output_dir
+    csv_name = sanitize_filename(f"{student_id}_{subject_id}_{filename_prefix}.csv")
     csv_path = os.path.join(output_dir, csv_name)
     df = pd.DataFrame(list(answers.items()), columns=["Question", "Answer"])
     for k,v in info.items(): df[k] = v
@@ -79,12 +83,18 @@ def build_answer_table(answers):
         data.append({"Q": q, "Answer": ans})
     return pd.DataFrame(data)
 
+# ---- Cleanup of old results, one sweeper thread per server process ----
+@st.cache_resource
+def start_results_cleanup(output_dir="results"):
+    return start_cleanup_thread(output_dir)
+
 # ---- Streamlit UI ----
 st.set_page_config(page_title="Grader OCR Numeric", layout="wide")
+start_results_cleanup()
 st.title("📄 Grader OCR - Numeric Answers")
 
 num_questions_estimate = st.number_input("Max questions to detect", min_value=10, max_value=200, value=NUM_QUESTIONS)
-uploaded = st.file_uploader("Upload scanned sheet", type=["jpg","jpeg","png"], accept_multiple_files=True)
+uploaded = st.file_uploader("Upload scanned sheets, or a zip of them", type=["jpg","jpeg","png","zip"], accept_multiple_files=True)
 
 # --- Metadata boxes (adjust per template) ---
 BOXES = {
@@ -97,12 +107,35 @@ BOXES = {
 }
 
 if uploaded:
-    all_tables=[]
+    try: check_request_size([up.size for up in uploaded])
+    except UploadRejected as e:
+        st.error(f"{e} ({e.status})")
+        st.stop()
+    # A zip upload is expanded into its images
+    sheets=[]
     for up in uploaded:
-        st.subheader(up.name)
-        try: upload_img = Image.open(up).convert("RGB")
+        if not up.name.lower().endswith(".zip"):
+            sheets.append((up.name, up.getvalue()))
+            continue
+        try: images, rejected = extract_zip_images(up.getvalue())
+        except UploadRejected as e:
+            st.error(f"Rejected {up.name}: {e} ({e.status})")
+            continue
+        for entry_name in rejected:
+            st.error(f"Rejected entry {entry_name} of {up.name}: path outside the archive")
+        st.info(f"Extracted {len(images)} image(s) from {up.name}")
+        sheets.extend(images)
+    all_tables=[]
+    for sheet_name, data in sheets:
+        try: up_name = check_upload(sheet_name, data)
+        except UploadRejected as e:
+            st.error(f"Rejected {e} ({e.status})")
+            continue
+        st.subheader(up_name)
+        # Phone photos carry an EXIF orientation, rotate them upright before cropping boxes
+        try: upload_img = ImageOps.exif_transpose(Image.open(io.BytesIO(data))).convert("RGB")
         except Exception as e:
-            st.error(f"Cannot open {up.name}: {e}")
+            st.error(f"Cannot open {up_name}: {e}")
             continue
 
         st.image(upload_img, caption="Uploaded sheet", use_column_width=True)
@@ -128,9 +161,9 @@ if uploaded:
         st.dataframe(df_table, use_container_width=True)
 
         # --- Save CSV ---
-        csv_path, df = save_results_csv(info, answers, filename_prefix=up.name.split(".")[0])
+        csv_path, df = save_results_csv(info, answers, filename_prefix=up_name.split(".")[0])
         csv_bytes = df_table.to_csv(index=False).encode("utf-8-sig")
-        st.download_button("Download Answer Table CSV", csv_bytes, f"{up.name.split('.')[0]}_answers.csv", "text/csv")
+        st.download_button("Download Answer Table CSV", csv_bytes, f"{up_name.split('.')[0]}_answers.csv", "text/csv")
         all_tables.append(df_table)
 
     # --- Combined table if multiple files ---
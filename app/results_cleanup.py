@@ -0,0 +1,37 @@
+import os
+import shutil
+import threading
+import time
+
+# Grader results older than this are deleted, the sweep runs every RESULTS_SWEEP_MINUTES
+RESULTS_TTL_SECONDS = float(os.getenv("RESULTS_TTL_HOURS", "72")) * 3600
+SWEEP_INTERVAL_SECONDS = float(os.getenv("RESULTS_SWEEP_MINUTES", "60")) * 60
+
+
+def remove_expired_results(output_dir, ttl_seconds=RESULTS_TTL_SECONDS, now=None):
+    '''Delete the files and directories in output_dir last modified over ttl_seconds ago, returns their names.'''
+    if not os.path.isdir(output_dir):
+        return []
+    now = time.time() if now is None else now
+    removed = []
+    for entry in os.scandir(output_dir):
+        if now - entry.stat().st_mtime <= ttl_seconds:
+            continue
+        if entry.is_dir(follow_symlinks=False):
+            shutil.rmtree(entry.path, ignore_errors=True)
+        else:
+            os.remove(entry.path)
+        removed.append(entry.name)
+    return sorted(removed)
+
+
+def start_cleanup_thread(output_dir, ttl_seconds=RESULTS_TTL_SECONDS, interval_seconds=SWEEP_INTERVAL_SECONDS):
+    '''Sweep output_dir in a daemon thread, call it once per process (e.g. under st.cache_resource).'''
+    def sweep_forever():
+        while True:
+            remove_expired_results(output_dir, ttl_seconds)
+            time.sleep(interval_seconds)
+
+    thread = threading.Thread(target=sweep_forever, name="results-cleanup", daemon=True)
+    thread.start()
+    return thread
@@ -0,0 +1,84 @@
+import io
+import os
+import re
+import zipfile
+
+# Upload limits in bytes, Streamlit's own server.maxUploadSize is much larger
+MAX_FILE_BYTES = int(os.getenv("MAX_UPLOAD_FILE_MB", "20")) * 1024 * 1024
+MAX_REQUEST_BYTES = int(os.getenv("MAX_UPLOAD_REQUEST_MB", "100")) * 1024 * 1024
+MAX_EXTRACTED_BYTES = int(os.getenv("MAX_UPLOAD_EXTRACTED_MB", "500")) * 1024 * 1024
+
+# Leading bytes of the image formats the grader accepts
+IMAGE_SIGNATURES = {
+    b"\xff\xd8\xff": "jpeg",
+    b"\x89PNG\r\n\x1a\n": "png",
+}
+
+
+class UploadRejected(Exception):
+    '''Raised for an upload that must not reach the disk. status follows the HTTP codes.'''
+    def __init__(self, message, status):
+        super().__init__(message)
+        self.status = status
+
+
+def sanitize_filename(name, fallback="upload"):
+    '''Keep only the basename of a client supplied name, without any path or odd characters.'''
+    name = os.path.basename(name.replace("\\", "/"))
+    name = re.sub(r"[^\w.-]", "_", name).lstrip(".")
+    return name or fallback
+
+
+def sniff_image_type(data):
+    for signature, image_type in IMAGE_SIGNATURES.items():
+        if data.startswith(signature):
+            return image_type
+    return None
+
+
+def check_request_size(sizes, max_request_bytes=MAX_REQUEST_BYTES):
+    total_bytes = sum(sizes)
+    if total_bytes > max_request_bytes:
+        raise UploadRejected(f"Uploads total {total_bytes} bytes, the limit is {max_request_bytes}", 413)
+
+
+def is_unsafe_entry_name(name):
+    parts = name.replace("\\", "/").split("/")
+    return name.startswith(("/", "\\")) or ":" in parts[0] or ".." in parts
+
+
+def extract_zip_images(data, max_extracted_bytes=MAX_EXTRACTED_BYTES):
+    '''Returns the (name, bytes) of the images in a zip upload and the names of the rejected path traversal entries.
+    Other entries are ignored, nothing is written to the disk.'''
+    try:
+        archive = zipfile.ZipFile(io.BytesIO(data))
+    except zipfile.BadZipFile:
+        raise UploadRejected("The upload is not a valid zip file", 415)
+    entries = [info for info in archive.infolist() if not info.is_dir()]
+    # file_size is only the declared size, the reads below are capped as well
+    declared_bytes = sum(info.file_size for info in entries)
+    if declared_bytes > max_extracted_bytes:
+        raise UploadRejected(f"The zip extracts to {declared_bytes} bytes, the limit is {max_extracted_bytes}", 413)
+    images, rejected, extracted_bytes = [], [], 0
+    for info in entries:
+        if is_unsafe_entry_name(info.filename):
+            rejected.append(info.filename)
+            continue
+        with archive.open(info) as entry:
+            entry_data = entry.read(max_extracted_bytes - extracted_bytes + 1)
+        extracted_bytes += len(entry_data)
+        if extracted_bytes > max_extracted_bytes:
+            raise UploadRejected(f"The zip extracts to over {max_extracted_bytes} bytes", 413)
+        if sniff_image_type(entry_data) is not None:
+            images.append((sanitize_filename(info.filename), entry_data))
+    return images, rejected
+
+
+def check_upload(name, data, max_file_bytes=MAX_FILE_BYTES):
+    '''Validate one uploaded file, returns its sanitized name or raises UploadRejected.'''
+    safe_name = sanitize_filename(name)
+    if len(data) > max_file_bytes:
+        raise UploadRejected(f"{safe_name} is {len(data)} bytes, the limit is {max_file_bytes}", 413)
+    if sniff_image_type(data) is None:
+        raise UploadRejected(f"{safe_name} is not a JPEG or PNG image", 415)
+    return safe_name
@@ -0,0 +1,102 @@
+"""
+Times the main detection stages on synthetic sheets, as a baseline for
+performance changes. Usage: python3 scripts/benchmark.py [--repeat N]
+"""
+import argparse
+import json
+import sys
+import tempfile
+from pathlib import Path
+from timeit import repeat
+
+import cv2
+import numpy as np
+
+sys.path.insert(0, str(Path(__file__).resolve().parents[1]))
+
+from src.defaults import CONFIG_DEFAULTS  # noqa: E402
+from src.template import Template  # noqa: E402
+from src.utils.image import RESIZE_INTERPOLATIONS, ImageUtils  # noqa: E402
+from src.utils.layout import render_template_to_image  # noqa: E402
+
+# Input resolutions of a phone photo, a 150dpi and a 300dpi A4 scan
+IMAGE_SIZES = [(1200, 1600), (1240, 1754), (2480, 3508)]
+TEMPLATE = {
+    "pageDimensions": [1200, 1600],
+    "bubbleDimensions": [40, 40],
+    "preProcessors": [{"name": "GaussianBlur", "options": {"kSize": [3, 3]}}],
+    "fieldBlocks": {
+        f"MCQ_Block_{column + 1}": {
+            "fieldType": "QTYPE_MCQ4",
+            "origin": [100 + 280 * column, 100],
+            "fieldLabels": [f"q{25 * column + 1}..{25 * column + 25}"],
+            "bubblesGap": 60,
+            "labelsGap": 55,
+        }
+        for column in range(4)
+    },
+}
+
+
+def draw_sheet(template, width, height):
+    # One filled bubble per question
+    marks = {}
+    for field_block in template.field_blocks:
+        for row, field_bubbles in enumerate(field_block.traverse_bubbles):
+            bubble = field_bubbles[row % len(field_bubbles)]
+            marks[bubble.field_label] = bubble.field_value
+    sheet = render_template_to_image(template, marks)
+    return cv2.resize(sheet, (width, height))
+
+
+def time_stage(stage, repeat_count):
+    return min(repeat(stage, number=1, repeat=repeat_count))
+
+
+def run_benchmarks(repeat_count):
+    with tempfile.TemporaryDirectory() as temp_dir:
+        template_path = Path(temp_dir, "template.json")
+        with open(template_path, "w") as f:
+            json.dump(TEMPLATE, f)
+        template = Template(template_path, CONFIG_DEFAULTS)
+    instance_ops = template.image_instance_ops
+
+    print(f"{'image size': <12} {'stage': <22} {'best of ' + str(repeat_count): >12}")
+    for width, height in IMAGE_SIZES:
+        image = draw_sheet(template, width, height)
+        processed = instance_ops.apply_preprocessors("sheet.png", image, template)
+        q_vals = np.random.default_rng(0).integers(0, 256, 400).tolist()
+        stages = {
+            "apply_preprocessors": lambda: instance_ops.apply_preprocessors(
+                "sheet.png", image, template
+            ),
+            "read_omr_response": lambda: instance_ops.read_omr_response(
+                template, processed, "sheet.png"
+            ),
+            "adjust_gamma": lambda: ImageUtils.adjust_gamma(
+                image, CONFIG_DEFAULTS.threshold_params.GAMMA_LOW
+            ),
+            "get_global_threshold": lambda: instance_ops.get_global_threshold(
+                q_vals, plot_show=False
+            ),
+        }
+        dimensions = CONFIG_DEFAULTS.dimensions
+        for interpolation in RESIZE_INTERPOLATIONS:
+            stages[f"resize ({interpolation})"] = (
+                lambda interpolation=interpolation: ImageUtils.resize_util(
+                    image,
+                    dimensions.processing_width,
+                    dimensions.processing_height,
+                    interpolation,
+                )
+            )
+        for stage_name, stage in stages.items():
+            seconds = time_stage(stage, repeat_count)
+            print(f"{width}x{height: <7} {stage_name: <22} {seconds * 1000:>9.2f} ms")
+
+
+if __name__ == "__main__":
+    argparser = argparse.ArgumentParser()
+    argparser.add_argument("--repeat", type=int, default=5, dest="repeat")
+    args = argparser.parse_args()
+    run_benchmarks(args.repeat)
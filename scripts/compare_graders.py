@@ -0,0 +1,49 @@
+"""
+Reports the agreement between two graders of the same sheets, e.g. a Results
+csv and a human-keyed copy of it. Usage:
+python3 scripts/compare_graders.py machine.csv human.csv [--fields q1 q2 ...]
+"""
+import argparse
+import json
+import sys
+from pathlib import Path
+
+import pandas as pd
+
+sys.path.insert(0, str(Path(__file__).resolve().parents[1]))
+
+from src.evaluation import get_agreement_report  # noqa: E402
+
+# Columns of a Results csv that are not responses
+NON_FIELD_COLUMNS = {
+    "file_id",
+    "input_path",
+    "output_path",
+    "score",
+    "file_confidence",
+    "multi_marked",
+    "is_blank",
+    "needs_review",
+    "blur_score",
+    "contrast_score",
+    "manually_corrected",
+}
+
+
+if __name__ == "__main__":
+    argparser = argparse.ArgumentParser()
+    argparser.add_argument("results_a")
+    argparser.add_argument("results_b")
+    argparser.add_argument("--fields", nargs="+", default=None, dest="fields")
+    args = argparser.parse_args()
+
+    results_a, results_b = (
+        pd.read_csv(path, dtype=str, keep_default_na=False)
+        for path in [args.results_a, args.results_b]
+    )
+    fields = args.fields or [
+        column
+        for column in results_a.columns
+        if column in results_b.columns and column not in NON_FIELD_COLUMNS
+    ]
+    print(json.dumps(get_agreement_report(results_a, results_b, fields), indent=4))
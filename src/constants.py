@@ -12,8 +12,13 @@ from dotmap import DotMap
 TEMPLATE_FILENAME = "template.json"
 EVALUATION_FILENAME = "evaluation.json"
 CONFIG_FILENAME = "config.json"
+# Manual corrections of misread fields: file id -> field label -> value
+CORRECTIONS_FILENAME = "corrections.json"
 
 FIELD_LABEL_NUMBER_REGEX = r"([^\d]+)(\d*)"
+
+# Templates without a schemaVersion are of version 1
+TEMPLATE_SCHEMA_VERSION = 2
 #
 ERROR_CODES = DotMap(
     {
@@ -23,6 +28,7 @@ ERROR_CODES = DotMap(
     _dynamic=False,
 )
 
+MANUAL_FIELD_TYPE = "QTYPE_MANUAL"
 FIELD_TYPES = {
     "QTYPE_INT": {
         "bubbleValues": ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"],
@@ -40,7 +46,9 @@ FIELD_TYPES = {
     "QTYPE_TEST": {
         "bubbleValues": ["+", "-", "+-", "0", "1", "2", "3", "4", "5", "6", "7", "8", "9"],
         "direction": "horizontal"
-    }
+    },
+    # A free-form box like a signature, read only as marked or blank and never scored
+    MANUAL_FIELD_TYPE: {"bubbleValues": ["MARKED"], "direction": "horizontal"},
     #
     # You can create and append custom field types here-
     #
@@ -52,7 +60,3 @@ CLR_BLACK = (50, 150, 150)
 CLR_WHITE = (250, 250, 250)
 CLR_GRAY = (130, 130, 130)
 CLR_DARK_GRAY = (100, 100, 100)
-
-# TODO: move to config.json
-GLOBAL_PAGE_THRESHOLD_WHITE = 200
-GLOBAL_PAGE_THRESHOLD_BLACK = 100
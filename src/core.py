@@ -1,5 +1,6 @@
 import os
 from collections import defaultdict
+from time import perf_counter
 from typing import Any
 
 import cv2
@@ -21,6 +22,8 @@ class ImageInstanceOps:
         super().__init__()
         self.tuning_config = tuning_config
         self.save_image_level = tuning_config.outputs.save_image_level
+        # Page threshold calibrated on the reference strips of the current image
+        self.reference_threshold = None
 
     def apply_preprocessors(self, file_path, in_omr, template):
         tuning_config = self.tuning_config
@@ -29,21 +32,61 @@ class ImageInstanceOps:
             in_omr,
             tuning_config.dimensions.processing_width,
             tuning_config.dimensions.processing_height,
+            tuning_config.dimensions.resize_interpolation,
         )
+        # Negative sheets have light marks on dark paper
+        if tuning_config.threshold_params.PAGE_TYPE_FOR_THRESHOLD in ["black", "dark"]:
+            in_omr = cv2.bitwise_not(in_omr)
 
         # run pre_processors in sequence
         for pre_processor in template.pre_processors:
             in_omr = pre_processor.apply_filter(in_omr, file_path)
         return in_omr
 
-    def read_omr_response(self, template, image, name, save_dir=None):
+    def is_below_min_resolution(self, image):
+        dimensions = self.tuning_config.dimensions
+        min_side = self.tuning_config.inputs.min_resolution_ratio * min(
+            dimensions.processing_width, dimensions.processing_height
+        )
+        return min(image.shape[:2]) < min_side
+
+    @staticmethod
+    def get_aspect_deviation(image, template):
+        page_width, page_height = template.page_dimensions
+        image_height, image_width = image.shape[:2]
+        return abs((image_height / image_width) / (page_height / page_width) - 1)
+
+    def read_omr_response(
+        self,
+        template,
+        image,
+        name,
+        save_dir=None,
+        auto_align=None,
+        stage_timings=None,
+    ):
         config = self.tuning_config
-        auto_align = config.alignment_params.auto_align
+        if auto_align is None:
+            auto_align = config.alignment_params.auto_align
+        self.reference_threshold = None
         try:
             img = image.copy()
             # origDim = img.shape[:2]
             img = ImageUtils.resize_util(
-                img, template.page_dimensions[0], template.page_dimensions[1]
+                img,
+                template.page_dimensions[0],
+                template.page_dimensions[1],
+                config.dimensions.resize_interpolation,
+            )
+            if template.region_of_interest is not None:
+                img = self.mask_outside_region(img, template.region_of_interest)
+            # Measured before normalization which would amplify the noise of a blank page
+            ink_ratio = self.get_ink_ratio(
+                self.crop_to_region(img, template.region_of_interest)
+            )
+            blur_score, contrast_score = (
+                ImageUtils.get_blur_score(img),
+                ImageUtils.get_contrast_score(img),
             )
             if img.max() > img.min():
                 img = ImageUtils.normalize_util(img)
@@ -54,6 +97,7 @@ class ImageInstanceOps:
             morph = img.copy()
             self.append_save_img(3, morph)
 
+            align_start = perf_counter()
             if auto_align:
                 # Note: clahe is good for morphology, bad for thresholding
                 morph = CLAHE_HELPER.apply(morph)
@@ -74,6 +118,11 @@ class ImageInstanceOps:
             alpha = 0.65
             omr_response = {}
             multi_marked, multi_roll = 0, 0
+            field_confidences, multi_marked_fields, review_fields = {}, [], []
+            # Marked values of multi-marked fields, darkest first
+            multi_marked_values = {}
+            # Marked values of each field, for the bubble mark heatmap
+            marked_bubbles = {}
 
             # TODO Make this part useful for visualizing status checks
             # blackVals=[0]
@@ -188,6 +237,8 @@ class ImageInstanceOps:
                     #   field_block.shift,", dimensions:", field_block.dimensions,
                     #   "origin:", field_block.origin,'\n')
                 # print("End Alignment")
+            if stage_timings is not None:
+                stage_timings["align"] = round(perf_counter() - align_start, 4)
 
             final_align = None
             if config.outputs.show_image_level >= 2:
@@ -207,18 +258,13 @@ class ImageInstanceOps:
             all_q_vals, all_q_strip_arrs, all_q_std_vals = [], [], []
             total_q_strip_no = 0
             for field_block in template.field_blocks:
-                box_w, box_h = field_block.bubble_dimensions
+                if field_block.is_manual:
+                    continue
                 q_std_vals = []
                 for field_block_bubbles in field_block.traverse_bubbles:
-                    q_strip_vals = []
-                    for pt in field_block_bubbles:
-                        # shifted
-                        x, y = (pt.x + field_block.shift, pt.y)
-                        rect = [y, y + box_h, x, x + box_w]
-                        q_strip_vals.append(
-                            cv2.mean(img[rect[0] : rect[1], rect[2] : rect[3]])[0]
-                            # detectCross(img, rect) ? 100 : 0
-                        )
+                    q_strip_vals = self.get_strip_means(
+                        img, field_block, field_block_bubbles
+                    )
                     q_std_vals.append(round(np.std(q_strip_vals), 2))
                     all_q_strip_arrs.append(q_strip_vals)
                     # _, _, _ = get_global_threshold(q_strip_vals, "QStrip Plot",
@@ -241,6 +287,11 @@ class ImageInstanceOps:
             # to support show_image_level
             # , "Mean Intensity Histogram",plot_show=True, sort_in_plot=True)
             global_thr, _, _ = self.get_global_threshold(all_q_vals, looseness=4)
+            if len(template.reference_strips) > 0:
+                self.reference_threshold = self.get_reference_threshold(
+                    img, template.reference_strips, all_q_vals
+                )
+                global_thr = self.reference_threshold
 
             logger.info(
                 f"Thresholding: \tglobal_thr: {round(global_thr, 2)} \tglobal_std_THR: {round(global_std_thresh, 2)}\t{'(Looks like a Xeroxed OMR)' if (global_thr == 255) else ''}"
@@ -258,6 +309,11 @@ class ImageInstanceOps:
 
             per_omr_threshold_avg, total_q_strip_no, total_q_box_no = 0, 0, 0
             for field_block in template.field_blocks:
+                if field_block.is_manual:
+                    self.read_manual_fields(
+                        img, field_block, omr_response, final_marked
+                    )
+                    continue
                 block_q_strip_no = 1
                 box_w, box_h = field_block.bubble_dimensions
                 shift = field_block.shift
@@ -270,13 +326,22 @@ class ImageInstanceOps:
                     no_outliers = all_q_std_vals[total_q_strip_no] < global_std_thresh
                     # print(total_q_strip_no, field_block_bubbles[0].field_label,
                     #   all_q_std_vals[total_q_strip_no], "no_outliers:", no_outliers)
-                    per_q_strip_threshold = self.get_local_threshold(
+                    per_q_strip_threshold = self.get_strip_threshold(
                         all_q_strip_arrs[total_q_strip_no],
                         global_thr,
                         no_outliers,
                         f"Mean Intensity Histogram for {key}.{field_block_bubbles[0].field_label}.{block_q_strip_no}",
                         config.outputs.show_image_level >= 6,
                     )
+                    strategies_agree, ensemble_confidence = True, None
+                    if config.threshold_params.DETECTION_STRATEGY == "ensemble":
+                        (
+                            per_q_strip_threshold,
+                            ensemble_confidence,
+                            strategies_agree,
+                        ) = self.get_ensemble_threshold(
+                            all_q_strip_arrs[total_q_strip_no], per_q_strip_threshold
+                        )
                     # print(field_block_bubbles[0].field_label,key,block_q_strip_no, "THR: ",
                     #   round(per_q_strip_threshold,2))
                     per_omr_threshold_avg += per_q_strip_threshold
@@ -300,11 +365,33 @@ class ImageInstanceOps:
                         total_q_box_no += 1
                         if bubble_is_marked:
                             detected_bubbles.append(bubble)
-                            x, y, field_value = (
-                                bubble.x + field_block.shift,
-                                bubble.y,
-                                bubble.field_value,
+
+                    field_label = field_block_bubbles[0].field_label
+                    field_confidences[field_label] = (
+                        ensemble_confidence
+                        if ensemble_confidence is not None
+                        else self.get_field_confidence(
+                            all_q_strip_arrs[total_q_strip_no], per_q_strip_threshold
+                        )
+                    )
+                    # Dropped before drawing, so that erasures are shown as unmarked
+                    detected_bubbles = self.drop_erased_bubbles(
+                        detected_bubbles,
+                        dict(
+                            zip(
+                                field_block_bubbles,
+                                all_q_strip_arrs[total_q_strip_no],
                             )
+                        ),
+                        per_q_strip_threshold,
+                    )
+                    for bubble in field_block_bubbles:
+                        x, y, field_value = (
+                            bubble.x + field_block.shift,
+                            bubble.y,
+                            bubble.field_value,
+                        )
+                        if bubble in detected_bubbles:
                             cv2.rectangle(
                                 final_marked,
                                 (int(x + box_w / 12), int(y + box_h / 12)),
@@ -337,6 +424,29 @@ class ImageInstanceOps:
                                 -1,
                             )
 
+                    marked_bubbles[field_label] = [
+                        bubble.field_value for bubble in detected_bubbles
+                    ]
+                    if not strategies_agree or self.get_needs_review(
+                        all_q_strip_arrs[total_q_strip_no],
+                        per_q_strip_threshold,
+                        field_confidences[field_label],
+                    ):
+                        review_fields.append(field_label)
+                    if len(detected_bubbles) > 1:
+                        multi_marked_fields.append(field_label)
+                        multi_marked_values[field_label] = [
+                            bubble.field_value
+                            for _, bubble in sorted(
+                                zip(
+                                    all_q_strip_arrs[total_q_strip_no],
+                                    field_block_bubbles,
+                                ),
+                                key=lambda q_val_and_bubble: q_val_and_bubble[0],
+                            )
+                            if bubble in detected_bubbles
+                        ]
+
                     for bubble in detected_bubbles:
                         field_label, field_value = (
                             bubble.field_label,
@@ -368,8 +478,24 @@ class ImageInstanceOps:
                     total_q_strip_no += 1
                 # /for field_block
 
-            per_omr_threshold_avg /= total_q_strip_no
+            # A template with only manual fields has no bubble strips
+            if total_q_strip_no > 0:
+                per_omr_threshold_avg /= total_q_strip_no
             per_omr_threshold_avg = round(per_omr_threshold_avg, 2)
+            omr_metrics = {
+                "field_confidences": field_confidences,
+                "file_confidence": self.get_file_confidence(
+                    field_confidences, marked_bubbles
+                ),
+                "multi_marked_fields": multi_marked_fields,
+                "multi_marked_values": multi_marked_values,
+                "marked_bubbles": marked_bubbles,
+                "needs_review": review_fields,
+                "ink_ratio": ink_ratio,
+                "is_blank": self.is_blank_page(ink_ratio),
+                "blur_score": blur_score,
+                "contrast_score": contrast_score,
+            }
             # Translucent
             cv2.addWeighted(
                 final_marked, alpha, transp_layer, 1 - alpha, 0, final_marked
@@ -420,7 +546,7 @@ class ImageInstanceOps:
                 for i in range(config.outputs.save_image_level):
                     self.save_image_stacks(i + 1, name, save_dir)
 
-            return omr_response, final_marked, multi_marked, multi_roll
+            return omr_response, final_marked, multi_marked, multi_roll, omr_metrics
 
         except Exception as e:
             raise e
@@ -521,20 +647,15 @@ class ImageInstanceOps:
 
         """
         config = self.tuning_config
-        PAGE_TYPE_FOR_THRESHOLD, MIN_JUMP, JUMP_DELTA = map(
+        MIN_JUMP, JUMP_DELTA = map(
             config.threshold_params.get,
             [
-                "PAGE_TYPE_FOR_THRESHOLD",
                 "MIN_JUMP",
                 "JUMP_DELTA",
             ],
         )
 
-        global_default_threshold = (
-            constants.GLOBAL_PAGE_THRESHOLD_WHITE
-            if PAGE_TYPE_FOR_THRESHOLD == "white"
-            else constants.GLOBAL_PAGE_THRESHOLD_BLACK
-        )
+        global_default_threshold = self.get_global_default_threshold()
 
         # Sort the Q bubbleValues
         # TODO: Change var name of q_vals
@@ -590,6 +711,335 @@ class ImageInstanceOps:
 
         return global_thr, j_low, j_high
 
+    def get_reference_threshold(self, image, reference_strips, q_vals):
+        """
+        The reference strips are the fully marked anchor and the lightest bubbles
+            the paper one, so faint and dark scans get the same relative cutoff.
+        """
+        strip_darkness = np.mean(
+            [
+                cv2.mean(image[y : y + h, x : x + w])[0]
+                for x, y, w, h in reference_strips
+            ]
+        )
+        # Most bubbles of a sheet are unmarked
+        paper_lightness = np.percentile(q_vals, 90)
+        mark_ratio = self.tuning_config.threshold_params.REFERENCE_MARK_RATIO
+        return float(strip_darkness + mark_ratio * (paper_lightness - strip_darkness))
+
+    def get_global_default_threshold(self):
+        if self.reference_threshold is not None:
+            return self.reference_threshold
+        # Black and dark pages are inverted into white ones before detection
+        return self.tuning_config.threshold_params.GLOBAL_PAGE_THRESHOLD_WHITE
+
+    @staticmethod
+    def mask_outside_region(image, region):
+        # Whitens the page outside the region, so printed text there is never read
+        x, y, w, h = region
+        masked = np.full_like(image, 255)
+        masked[y : y + h, x : x + w] = image[y : y + h, x : x + w]
+        return masked
+
+    @staticmethod
+    def crop_to_region(image, region):
+        # The whitened rest of the page would make a small region look blank
+        if region is None:
+            return image
+        x, y, w, h = region
+        return image[y : y + h, x : x + w]
+
+    def read_barcode(self, template, image):
+        image = ImageUtils.resize_util(
+            image, template.page_dimensions[0], template.page_dimensions[1]
+        )
+        x, y, w, h = template.barcode_region
+        return ImageUtils.decode_qr_code(image[y : y + h, x : x + w])
+
+    def get_strip_means(self, image, field_block, field_bubbles):
+        box_w, box_h = field_block.bubble_dimensions
+        # shifted
+        positions = [
+            (bubble.x + field_block.shift, bubble.y) for bubble in field_bubbles
+        ]
+        if self.has_overlapping_boxes(positions, box_w, box_h):
+            q_strip_vals = self.get_closest_pixel_means(image, positions, box_w, box_h)
+        else:
+            q_strip_vals = [
+                self.get_bubble_mean(image, x, y, box_w, box_h) for x, y in positions
+            ]
+        if field_block.mark_style != "fill":
+            # A filled bubble still counts on a cross or tick field
+            q_strip_vals = [
+                min(q_val, self.get_stroke_mean(image, x, y, box_w, box_h))
+                for q_val, (x, y) in zip(q_strip_vals, positions)
+            ]
+        return q_strip_vals
+
+    @staticmethod
+    def has_overlapping_boxes(positions, box_w, box_h):
+        return any(
+            abs(x - other_x) < box_w and abs(y - other_y) < box_h
+            for index, (x, y) in enumerate(positions)
+            for other_x, other_y in positions[index + 1 :]
+        )
+
+    @staticmethod
+    def get_closest_pixel_means(image, positions, box_w, box_h):
+        """
+        On dense layouts the boxes of neighbouring bubbles overlap, so one mark in
+            the shared pixels would darken both of them. Each shared pixel is only
+            counted for the bubble whose center is the closest to it.
+        """
+        positions = [(max(0, x), max(0, y)) for x, y in positions]
+        centers = [(x + box_w / 2, y + box_h / 2) for x, y in positions]
+        offsets_y, offsets_x = np.mgrid[0:box_h, 0:box_w]
+        q_strip_vals = []
+        for index, (x, y) in enumerate(positions):
+            pixels_x, pixels_y = offsets_x + x, offsets_y + y
+            squared_distances = [
+                (pixels_x - center_x) ** 2 + (pixels_y - center_y) ** 2
+                for center_x, center_y in centers
+            ]
+            is_closest = squared_distances[index] <= np.min(squared_distances, axis=0)
+            box = image[y : y + box_h, x : x + box_w]
+            # The box is cut short at the image border
+            is_closest = is_closest[: box.shape[0], : box.shape[1]]
+            q_strip_vals.append(float(box[is_closest].mean()))
+        return q_strip_vals
+
+    def get_bubble_mean(self, image, x, y, box_w, box_h):
+        """
+        With a snap_radius, the box is moved within the radius to where its edge
+            contrasts most with the paper around it, to follow paper stretch. That
+            is the printed outline of an empty bubble or the edge of a filled one,
+            so empty bubbles are not pulled onto their ring as with the darkest
+            position. The radius is capped at a quarter of the bubble so that the
+            box cannot reach into a neighbouring bubble.
+        """
+        snap_radius = min(
+            self.tuning_config.alignment_params.snap_radius, box_w // 4, box_h // 4
+        )
+        if snap_radius == 0:
+            return self.get_region_mean(image, x, y, box_w, box_h)
+        offsets = range(-snap_radius, snap_radius + 1)
+        # Nearest offsets first, so that ties keep the template position
+        dx, dy = max(
+            sorted(
+                [(dx, dy) for dx in offsets for dy in offsets],
+                key=lambda offset: abs(offset[0]) + abs(offset[1]),
+            ),
+            key=lambda offset: self.get_edge_contrast(
+                image, x + offset[0], y + offset[1], box_w, box_h
+            ),
+        )
+        return self.get_region_mean(image, x + dx, y + dy, box_w, box_h)
+
+    @staticmethod
+    def get_edge_contrast(image, x, y, box_w, box_h):
+        # How much lighter the band around the box is than the band inside its edge
+        band = max(1, min(box_w, box_h) // 8)
+        inner_sum, inner_size = ImageInstanceOps.get_region_sum(
+            image, x + band, y + band, box_w - 2 * band, box_h - 2 * band
+        )
+        box_sum, box_size = ImageInstanceOps.get_region_sum(image, x, y, box_w, box_h)
+        outer_sum, outer_size = ImageInstanceOps.get_region_sum(
+            image, x - band, y - band, box_w + 2 * band, box_h + 2 * band
+        )
+        edge_mean = (box_sum - inner_sum) / max(1, box_size - inner_size)
+        around_mean = (outer_sum - box_sum) / max(1, outer_size - box_size)
+        return around_mean - edge_mean
+
+    @staticmethod
+    def get_region_sum(image, x, y, w, h):
+        # Pixel sum and count of the rectangle, clipped to the image
+        region = image[max(0, y) : max(0, y + h), max(0, x) : max(0, x + w)]
+        return float(np.sum(region)), region.size
+
+    def get_region_mean(self, image, x, y, box_w, box_h):
+        # Sample only the inside of the bubble box, leaving out the printed ring
+        inset = self.tuning_config.threshold_params.BUBBLE_INSET
+        inset_x, inset_y = round(box_w * inset), round(box_h * inset)
+        x, y = max(0, x), max(0, y)
+        rect = [y + inset_y, y + box_h - inset_y, x + inset_x, x + box_w - inset_x]
+        return cv2.mean(image[rect[0] : rect[1], rect[2] : rect[3]])[0]
+
+    def read_manual_fields(self, image, field_block, omr_response, final_marked):
+        """
+        Manual fields like a signature box are not bubbles. Each one is read as
+            marked when the ink inside it, leaving out its printed border, is at
+            least MANUAL_FIELD_INK_RATIO.
+        """
+        box_w, box_h = field_block.bubble_dimensions
+        inset_x, inset_y = box_w // 10, box_h // 10
+        min_ink_ratio = self.tuning_config.threshold_params.MANUAL_FIELD_INK_RATIO
+        for field_bubbles in field_block.traverse_bubbles:
+            bubble = field_bubbles[0]
+            x, y = bubble.x + field_block.shift, bubble.y
+            box = image[
+                y + inset_y : y + box_h - inset_y, x + inset_x : x + box_w - inset_x
+            ]
+            is_marked = self.get_ink_ratio(box) >= min_ink_ratio
+            omr_response[bubble.field_label] = (
+                bubble.field_value if is_marked else field_block.empty_val
+            )
+            cv2.rectangle(
+                final_marked,
+                (x, y),
+                (x + box_w, y + box_h),
+                constants.CLR_DARK_GRAY if is_marked else constants.CLR_GRAY,
+                3,
+            )
+
+    def get_stroke_mean(self, image, x, y, box_w, box_h):
+        """
+        The thin strokes of a cross or tick barely darken the mean of a bubble.
+            Instead, the fraction of the bubble inside lying on stroke edges is
+            returned on the intensity scale, a box full of strokes reads as black.
+        """
+        # A margin that leaves out the edges of the printed bubble outline
+        margin_x, margin_y = box_w // 6, box_h // 6
+        x, y = max(0, x), max(0, y)
+        box = image[
+            y + margin_y : y + box_h - margin_y, x + margin_x : x + box_w - margin_x
+        ].astype(np.float32)
+        gradient = cv2.magnitude(
+            cv2.Sobel(box, cv2.CV_32F, 1, 0), cv2.Sobel(box, cv2.CV_32F, 0, 1)
+        )
+        # An intensity step of MIN_JUMP, which the Sobel kernel weighs by four
+        edge_cutoff = 4 * self.tuning_config.threshold_params.MIN_JUMP
+        stroke_ratio = np.count_nonzero(gradient > edge_cutoff) / gradient.size
+        return 255 * (1 - stroke_ratio)
+
+    def get_ink_ratio(self, image):
+        dark_pixels = np.count_nonzero(image < self.get_dark_pixel_cutoff(image))
+        return round(dark_pixels / image.size, 4)
+
+    def get_dark_pixel_cutoff(self, image):
+        cutoff = self.tuning_config.threshold_params.DARK_PIXEL_CUTOFF
+        if cutoff == "page":
+            return self.get_global_default_threshold()
+        if cutoff == "auto":
+            # Follows the midpoint of gamma shifted scans, Otsu's threshold itself
+            # is the lightest intensity of the dark class
+            otsu_threshold, _ = cv2.threshold(
+                image, 0, 255, cv2.THRESH_BINARY + cv2.THRESH_OTSU
+            )
+            return otsu_threshold + 1
+        return cutoff
+
+    def is_blank_page(self, ink_ratio):
+        return ink_ratio < self.tuning_config.threshold_params.BLANK_PAGE_INK_RATIO
+
+    def drop_erased_bubbles(self, detected_bubbles, q_val_by_bubble, threshold):
+        """
+        An erased and re-marked answer leaves a faint ghost next to the final mark.
+            When the darkest marked bubble is ERASURE_DARKNESS_RATIO times deeper
+            below the threshold than every other marked one, those are erasures.
+        """
+        erasure_ratio = self.tuning_config.threshold_params.ERASURE_DARKNESS_RATIO
+        if erasure_ratio == 0 or len(detected_bubbles) < 2:
+            return detected_bubbles
+        by_depth = sorted(
+            detected_bubbles,
+            key=lambda bubble: threshold - q_val_by_bubble[bubble],
+            reverse=True,
+        )
+        darkest_depth, second_depth = (
+            threshold - q_val_by_bubble[bubble] for bubble in by_depth[:2]
+        )
+        if darkest_depth >= erasure_ratio * second_depth:
+            return by_depth[:1]
+        return detected_bubbles
+
+    def get_strip_threshold(
+        self, q_vals, global_thr, no_outliers, plot_title=None, plot_show=True
+    ):
+        """
+        "relative_jump": the threshold is placed in the largest jump between the
+            bubble intensities of the strip, so a uniformly dark or light scan
+            still separates the marked bubble(s) from the rest.
+        "absolute": every bubble darker than the page threshold is marked,
+            irrespective of the other bubbles in the strip.
+        "ensemble": returns the "relative_jump" threshold, which is then checked
+            against the "absolute" one in get_ensemble_threshold.
+        """
+        if self.tuning_config.threshold_params.DETECTION_STRATEGY == "absolute":
+            return self.get_global_default_threshold()
+        return self.get_local_threshold(
+            q_vals, global_thr, no_outliers, plot_title, plot_show
+        )
+
+    def get_ensemble_threshold(self, q_vals, relative_threshold):
+        """
+        Runs both strategies on the strip and keeps the threshold of the more
+            confident one. When they mark the same bubbles, their confidences are
+            combined as independent evidence, otherwise the field needs a review.
+        Returns (threshold, confidence, strategies_agree)
+        """
+        absolute_threshold = self.get_global_default_threshold()
+        relative_confidence, absolute_confidence = (
+            self.get_field_confidence(q_vals, relative_threshold),
+            self.get_field_confidence(q_vals, absolute_threshold),
+        )
+        strategies_agree = [relative_threshold > q_val for q_val in q_vals] == [
+            absolute_threshold > q_val for q_val in q_vals
+        ]
+        if relative_confidence >= absolute_confidence:
+            threshold, confidence = relative_threshold, relative_confidence
+        else:
+            threshold, confidence = absolute_threshold, absolute_confidence
+        if strategies_agree:
+            confidence = round(
+                1 - (1 - relative_confidence) * (1 - absolute_confidence), 2
+            )
+        return threshold, confidence, strategies_agree
+
+    def get_file_confidence(self, field_confidences, marked_bubbles):
+        aggregation = self.tuning_config.threshold_params.CONFIDENCE_AGGREGATION
+        confidences = list(field_confidences.values())
+        if not confidences:
+            # A template with only manual fields has nothing to be unsure of
+            return 1.0
+        if aggregation == "marked_only":
+            # The many confidently blank fields would hide an unsure mark otherwise
+            confidences = [
+                confidence
+                for field_label, confidence in field_confidences.items()
+                if len(marked_bubbles.get(field_label, [])) > 0
+            ] or confidences
+        if aggregation == "min_field":
+            return round(float(np.min(confidences)), 2)
+        if aggregation == "median":
+            return round(float(np.median(confidences)), 2)
+        return round(float(np.mean(confidences)), 2)
+
+    def get_field_confidence(self, q_vals, threshold):
+        """
+        Confidence of a field is decided by the bubble closest to the threshold.
+        A bubble that is half a confident jump away from the threshold (or more)
+            gives full confidence, one lying on the threshold gives zero.
+        """
+        threshold_params = self.tuning_config.threshold_params
+        confident_jump = (
+            threshold_params.MIN_JUMP + threshold_params.CONFIDENT_SURPLUS
+        )
+        closest_distance = min(abs(threshold - q_val) for q_val in q_vals)
+        return round(min(1.0, 2 * closest_distance / confident_jump), 2)
+
+    def get_needs_review(self, q_vals, threshold, field_confidence):
+        """
+        A field needs a manual review when its detection is unsure, or when
+            the darkest (marked) bubble is hardly darker than the runner-up.
+        """
+        threshold_params = self.tuning_config.threshold_params
+        if field_confidence < threshold_params.REVIEW_CONFIDENCE_THRESHOLD:
+            return True
+        if len(q_vals) < 2:
+            return False
+        darkest, runner_up = sorted(q_vals)[:2]
+        return threshold > darkest and runner_up - darkest < threshold_params.MIN_JUMP
+
     def get_local_threshold(
         self, q_vals, global_thr, no_outliers, plot_title=None, plot_show=True
     ):
@@ -7,6 +7,22 @@ CONFIG_DEFAULTS = DotMap(
             "display_width": 1640,
             "processing_height": 820,
             "processing_width": 666,
+            # Note: 'nearest' is the fastest resize but can thin out faint marks, 'area' and 'lanczos' are slower and smoother
+            "resize_interpolation": "linear",
+        },
+        "inputs": {
+            # Note: stretches the intensities of 16-bit scans instead of truncating them to 8 bits
+            "preserve_bit_depth": False,
+            # Note: images whose smaller side is below this fraction of the processing dimensions are reported as errors
+            "min_resolution_ratio": 0.25,
+            # Note: warns when the image aspect ratio is this much off from the template page
+            "max_aspect_deviation": 0.5,
+            # Note: 'exact' skips byte-identical images in a directory, 'perceptual' also skips re-encoded copies
+            "deduplicate": "off",
+            # Note: a read taking longer fails the run with a timeout error instead of stalling it
+            "read_timeout_seconds": 30,
+            # Note: IO errors, e.g. of a network share, are retried this many times with a backoff. Undecodable files are not
+            "read_retries": 2,
         },
         "threshold_params": {
             "GAMMA_LOW": 0.7,
@@ -14,7 +30,32 @@ CONFIG_DEFAULTS = DotMap(
             "MIN_JUMP": 25,
             "CONFIDENT_SURPLUS": 5,
             "JUMP_DELTA": 30,
+            # Note: 'black' and 'dark' invert negative sheets (light marks on dark paper) before processing
             "PAGE_TYPE_FOR_THRESHOLD": "white",
+            # Note: 'relative_jump' compares bubbles within a field, 'absolute' uses the page threshold below,
+            #  'ensemble' runs both and flags the fields where they disagree for review
+            "DETECTION_STRATEGY": "relative_jump",
+            # Note: how the field confidences make up the file confidence. 'marked_only' leaves out the blank fields,
+            #  'min_field' takes the least confident field, which suits triage of sheets for review
+            "CONFIDENCE_AGGREGATION": "mean",
+            # Note: fallback mark threshold used when no clear jump is found in the bubble intensities
+            "GLOBAL_PAGE_THRESHOLD_WHITE": 200,
+            # Note: unused since black pages are inverted, kept for existing configs
+            "GLOBAL_PAGE_THRESHOLD_BLACK": 100,
+            # Note: pages with a smaller fraction of dark pixels are flagged as blank
+            "BLANK_PAGE_INK_RATIO": 0.01,
+            # Note: fraction of dark pixels above which a manual field (QTYPE_MANUAL) like a signature box is read as marked
+            "MANUAL_FIELD_INK_RATIO": 0.02,
+            # Note: intensity below which a pixel counts as ink, 'page' uses the global page threshold and 'auto' the Otsu threshold of each page
+            "DARK_PIXEL_CUTOFF": "page",
+            # Note: fraction of the bubble size left out on each side, so that the printed bubble ring is not sampled
+            "BUBBLE_INSET": 0,
+            # Note: a marked bubble this many times darker than the other marked ones in its field makes them erasures, 0 disables
+            "ERASURE_DARKNESS_RATIO": 0,
+            # Note: fields detected with a lower confidence are listed for manual review
+            "REVIEW_CONFIDENCE_THRESHOLD": 0.5,
+            # Note: with template referenceStrips, places the mark threshold this far from the strip darkness towards the paper
+            "REFERENCE_MARK_RATIO": 0.5,
         },
         "alignment_params": {
             # Note: 'auto_align' enables automatic template alignment, use if the scans show slight misalignments.
@@ -23,12 +64,38 @@ CONFIG_DEFAULTS = DotMap(
             "max_steps": 20,
             "stride": 1,
             "thickness": 3,
+            # Note: files detected with a lower mean confidence are retried with auto_align enabled, 0 disables the retry
+            "retry_below_confidence": 0,
+            # Note: moves each bubble box up to this many pixels onto the printed bubble or mark, 0 disables the snapping
+            "snap_radius": 0,
+        },
+        "auto_layout": {
+            # Note: used to detect the bubbles for a draft template in setLayout mode
+            "min_bubble_size": 5,
+            "max_aspect_deviation": 0.3,
+            "min_fill_ratio": 0.6,
+            "max_fill_ratio": 0.9,
         },
         "outputs": {
             "show_image_level": 0,
             "save_image_level": 0,
             "save_detections": True,
             "filter_out_multimarked_files": False,
+            "save_xlsx": False,
+            # Note: 'ndjson' streams one JSON result per file to stdout instead of writing the result files
+            "output_format": "files",
+            # Note: nests the outputs under the input directory name and writes AggregateResults.csv
+            "preserve_input_structure": False,
+            # Note: ';' with decimal_comma suits spreadsheets in locales that write 0,5 for a half
+            "csv_delimiter": ",",
+            "decimal_comma": False,
+            # Note: sheets with a lower file confidence are not scored but copied to Manual/RejectedFiles for a re-scan, 0 disables
+            "reject_below_confidence": 0,
+            # Note: writes how often each bubble was marked across the run, as Results/mark_heatmap.png and mark_counts.csv
+            "save_mark_heatmap": False,
+            # Note: adds a base64 JPEG thumbnail of the input image to each ndjson result, for review tools without access to the files
+            "embed_thumbnails": False,
+            "thumbnail_max_dim": 256,
         },
     },
     _dynamic=False,
@@ -2,5 +2,10 @@ TEMPLATE_DEFAULTS = {
     "preProcessors": [],
     "emptyValue": "",
     "customLabels": {},
+    "dateLabels": {},
+    "columns": [],
     "outputColumns": [],
+    "referenceStrips": [],
+    "valueMap": {},
+    "studentRegions": [],
 }
@@ -6,24 +6,80 @@
  Github: https://github.com/Udayraj123
 
 """
+import json
 import os
+import shutil
+import sys
+from copy import deepcopy
 from csv import QUOTE_NONNUMERIC
 from pathlib import Path
-from time import time
+from time import sleep, time
 
 import cv2
 import pandas as pd
+from dotmap import DotMap
 from rich.table import Table
 
 from src import constants
 from src.defaults import CONFIG_DEFAULTS
-from src.evaluation import EvaluationConfig, evaluate_concatenated_response
-from src.logger import console, logger
+from src.evaluation import (
+    EvaluationConfig,
+    VersionedEvaluationConfig,
+    build_gradebook,
+    evaluate_concatenated_response,
+    get_item_analysis,
+)
+from src.exceptions import (
+    ConfigInvalidError,
+    EvaluationInvalidError,
+    ImageReadError,
+    InputDirNotFoundError,
+    TemplateNotFoundError,
+)
+from src.logger import (
+    RunProgress,
+    console,
+    is_debug_enabled,
+    logger,
+    redirect_logs_to_stderr,
+    set_current_file,
+    time_stage,
+)
 from src.template import Template
-from src.utils.file import Paths, setup_dirs_for_paths, setup_outputs_for_template
+from src.utils.file import (
+    Paths,
+    append_results_row,
+    append_progress,
+    clear_in_progress,
+    clear_progress,
+    count_records,
+    find_duplicate_files,
+    get_added_records,
+    load_progress,
+    load_watch_manifest,
+    mark_in_progress,
+    restore_records,
+    save_watch_manifest,
+    setup_dirs_for_paths,
+    setup_outputs_for_template,
+    write_confidence_histogram,
+    write_outputs_manifest,
+    write_gradebook,
+    write_aggregate_results,
+    write_item_analysis,
+    write_mark_heatmap,
+    write_results_xlsx,
+)
 from src.utils.image import ImageUtils
 from src.utils.interaction import InteractionUtils, Stats
-from src.utils.parsing import get_concatenated_response, open_config_with_defaults
+from src.utils.layout import generate_draft_template
+from src.utils.parsing import (
+    apply_corrections,
+    get_concatenated_response,
+    get_invalid_dates,
+    open_config_with_defaults,
+    open_corrections_with_validation,
+)
 
 # Load processors
 STATS = Stats()
@@ -31,11 +87,64 @@ STATS = Stats()
 
 def entry_point(input_dir, args):
     if not os.path.exists(input_dir):
-        raise Exception(f"Given input directory does not exist: '{input_dir}'")
+        raise InputDirNotFoundError(
+            f"Given input directory does not exist: '{input_dir}'"
+        )
     curr_dir = input_dir
     return process_dir(input_dir, curr_dir, args)
 
 
+def set_threads_count(threads):
+    # OpenCV spreads its operations over all the cores unless limited
+    if threads is not None and threads > 0:
+        cv2.setNumThreads(threads)
+
+
+def watch_input_dirs(input_dirs, args, max_polls=None):
+    """
+    Keeps polling the input directories and processes the images added to them,
+        the results are appended to the existing output files.
+    """
+    for input_dir in input_dirs:
+        if not os.path.exists(input_dir):
+            raise InputDirNotFoundError(
+                f"Given input directory does not exist: '{input_dir}'"
+            )
+    logger.info(f"Watching {len(input_dirs)} input dir(s) for new images...")
+    # Output dir -> outputs namespace, so that the aggregates span all the polls
+    outputs_namespaces = {}
+    polls_count = 0
+    while max_polls is None or polls_count < max_polls:
+        for input_dir in input_dirs:
+            process_dir(
+                input_dir, input_dir, args, outputs_namespaces=outputs_namespaces
+            )
+        polls_count += 1
+        sleep(args["watch"])
+
+
+def get_unseen_stable_files(omr_files, watch_manifest, stable_seconds):
+    # Files modified within the last poll interval may still be being written
+    now = time()
+    return [
+        f
+        for f in omr_files
+        if watch_manifest.get(str(f)) != f.stat().st_mtime
+        and now - f.stat().st_mtime >= stable_seconds
+    ]
+
+
+def get_unfinished_files(omr_files, progress):
+    unfinished_files = []
+    for f in omr_files:
+        if str(f) not in progress:
+            unfinished_files.append(f)
+        elif progress[str(f)]["mtime"] != f.stat().st_mtime:
+            logger.warning(f"'{f}' has changed since it was processed, redoing it")
+            unfinished_files.append(f)
+    return unfinished_files
+
+
 def print_config_summary(
     curr_dir,
     omr_files,
@@ -71,6 +180,48 @@ def print_config_summary(
     console.print(table, justify="center")
 
 
+def print_dry_run_summary(curr_dir, omr_files, template, paths):
+    logger.info("")
+    table = Table(title="Dry Run Summary", show_header=False, show_lines=False)
+    table.add_column("Key", style="cyan", no_wrap=True)
+    table.add_column("Value", style="magenta")
+    table.add_row("Input Directory", f"{curr_dir.resolve()}")
+    table.add_row("Output Directory", f"{paths.output_dir.resolve()}")
+    table.add_row("Count of Images", f"{len(omr_files)}")
+    table.add_row("Detected Template Path", f"{template}")
+    table.add_row("Count of Field Blocks", f"{len(template.field_blocks)}")
+    console.print(table, justify="center")
+
+
+def apply_config_overrides(tuning_config, args):
+    # Command line flags take precedence over the values in config.json
+    mark_threshold, output_format, auto_align = map(
+        args.get, ["markThreshold", "format", "autoAlign"]
+    )
+    if mark_threshold is None and output_format is None and not auto_align:
+        return tuning_config
+
+    tuning_config = DotMap(deepcopy(tuning_config.toDict()), _dynamic=False)
+    if mark_threshold is not None:
+        if not 0 <= mark_threshold <= 255:
+            raise ConfigInvalidError(
+                f"Invalid mark threshold: {mark_threshold}, expected a value between 0 and 255"
+            )
+        page_type = tuning_config.threshold_params.PAGE_TYPE_FOR_THRESHOLD
+        # Black and dark pages are inverted into white ones before thresholding
+        if page_type in ["black", "dark"]:
+            page_type = "white"
+        tuning_config.threshold_params[
+            f"GLOBAL_PAGE_THRESHOLD_{page_type.upper()}"
+        ] = mark_threshold
+    if output_format is not None:
+        tuning_config.outputs.output_format = output_format
+    if auto_align:
+        # Shifts each field block to the detected columns before reading bubbles
+        tuning_config.alignment_params.auto_align = True
+    return tuning_config
+
+
 def process_dir(
     root_dir,
     curr_dir,
@@ -78,11 +229,16 @@ def process_dir(
     template=None,
     tuning_config=CONFIG_DEFAULTS,
     evaluation_config=None,
+    outputs_namespaces=None,
 ):
     # Update local tuning_config (in current recursion stack)
     local_config_path = curr_dir.joinpath(constants.CONFIG_FILENAME)
     if os.path.exists(local_config_path):
         tuning_config = open_config_with_defaults(local_config_path)
+    tuning_config = apply_config_overrides(tuning_config, args)
+    if tuning_config.outputs.output_format != "files":
+        # Also when the format comes from config.json instead of --format
+        redirect_logs_to_stderr()
 
     # Update local template (in current recursion stack)
     local_template_path = curr_dir.joinpath(constants.TEMPLATE_FILENAME)
@@ -93,14 +249,15 @@ def process_dir(
             tuning_config,
         )
     # Look for subdirectories for processing
-    subdirs = [d for d in curr_dir.iterdir() if d.is_dir()]
+    subdirs = sorted(
+        (d for d in curr_dir.iterdir() if d.is_dir()), key=get_path_sort_key
+    )
 
-    output_dir = Path(args["output_dir"], curr_dir.relative_to(root_dir))
+    output_dir = get_output_dir(root_dir, curr_dir, args, tuning_config)
     paths = Paths(output_dir)
 
     # look for images in current dir to process
-    exts = ("*.[pP][nN][gG]", "*.[jJ][pP][gG]", "*.[jJ][pP][eE][gG]")
-    omr_files = sorted([f for ext in exts for f in curr_dir.glob(ext)])
+    omr_files = get_omr_files(curr_dir)
 
     # Exclude images (take union over all pre_processors)
     excluded_files = []
@@ -125,41 +282,109 @@ def process_dir(
             Path(exclude_file) for exclude_file in evaluation_config.get_exclude_files()
         )
 
+    local_corrections_path = curr_dir.joinpath(constants.CORRECTIONS_FILENAME)
+    corrections = (
+        open_corrections_with_validation(local_corrections_path)
+        if os.path.exists(local_corrections_path)
+        else {}
+    )
+
+    answer_key_dir = args.get("answerKeyDir")
+    if not args["setLayout"] and answer_key_dir is not None and local_template_exists:
+        # The answer keys of the exam versions take the place of an evaluation.json
+        evaluation_config = VersionedEvaluationConfig(
+            Path(answer_key_dir), template, tuning_config
+        )
+
     omr_files = [f for f in omr_files if f not in excluded_files]
 
-    if omr_files:
+    if tuning_config.inputs.deduplicate != "off":
+        omr_files, duplicates = find_duplicate_files(
+            omr_files, tuning_config.inputs.deduplicate
+        )
+        for duplicate, canonical in duplicates.items():
+            logger.warning(f"Skipping '{duplicate}', a duplicate of '{canonical}'")
+
+    watch_seconds = args.get("watch")
+    if watch_seconds is not None:
+        watch_manifest = load_watch_manifest(paths)
+        omr_files = get_unseen_stable_files(omr_files, watch_manifest, watch_seconds)
+        if not omr_files and not subdirs:
+            return
+
+    resume = args.get("resume")
+    finished_files = []
+    if resume:
+        progress = load_progress(paths)
+        unfinished_files = get_unfinished_files(omr_files, progress)
+        finished_files = [f for f in omr_files if f not in unfinished_files]
+        logger.info(
+            f"Resuming in '{curr_dir}', skipping {len(finished_files)} processed file(s)"
+        )
+        omr_files = unfinished_files
+        if not omr_files and not subdirs:
+            return
+
+    if omr_files and not template and args["setLayout"]:
+        # Bootstrap a template from the first image for the user to hand-edit
+        write_draft_template(omr_files[0], paths, tuning_config)
+    elif omr_files:
         if not template:
             logger.error(
                 f"Found images, but no template in the directory tree \
                 of '{curr_dir}'. \nPlace {constants.TEMPLATE_FILENAME} in the \
                 appropriate directory."
             )
-            raise Exception(
+            raise TemplateNotFoundError(
                 f"No template file found in the directory tree of {curr_dir}"
             )
 
-        setup_dirs_for_paths(paths)
-        outputs_namespace = setup_outputs_for_template(paths, template)
-
-        print_config_summary(
-            curr_dir,
-            omr_files,
-            template,
-            tuning_config,
-            local_config_path,
-            evaluation_config,
-            args,
-        )
-        if args["setLayout"]:
-            show_template_layouts(omr_files, template, tuning_config)
+        if args.get("dryRun"):
+            # The template is already loaded and validated at this point
+            print_dry_run_summary(curr_dir, omr_files, template, paths)
         else:
-            process_files(
+            write_files = tuning_config.outputs.output_format != "ndjson"
+            if write_files:
+                setup_dirs_for_paths(paths)
+            if outputs_namespaces is not None and output_dir in outputs_namespaces:
+                outputs_namespace = outputs_namespaces[output_dir]
+            else:
+                outputs_namespace = setup_outputs_for_template(
+                    paths, template, tuning_config, write_files=write_files
+                )
+                # The aggregates also cover the files done before the interruption
+                for f in finished_files:
+                    restore_records(outputs_namespace, progress[str(f)]["records"])
+                if outputs_namespaces is not None:
+                    outputs_namespaces[output_dir] = outputs_namespace
+
+            print_config_summary(
+                curr_dir,
                 omr_files,
                 template,
                 tuning_config,
+                local_config_path,
                 evaluation_config,
-                outputs_namespace,
+                args,
             )
+            if args["setLayout"]:
+                show_template_layouts(omr_files, template, tuning_config)
+            else:
+                if not resume:
+                    clear_progress(paths)
+                process_files(
+                    omr_files,
+                    template,
+                    tuning_config,
+                    evaluation_config,
+                    outputs_namespace,
+                    corrections,
+                )
+                if watch_seconds is not None:
+                    watch_manifest.update(
+                        {str(f): f.stat().st_mtime for f in omr_files}
+                    )
+                    save_watch_manifest(paths, watch_manifest)
 
     elif not subdirs:
         # Each subdirectory should have images or should be non-leaf
@@ -177,14 +402,133 @@ def process_dir(
             template,
             tuning_config,
             evaluation_config,
+            outputs_namespaces,
+        )
+
+    if curr_dir == root_dir and tuning_config.outputs.preserve_input_structure:
+        write_aggregate_results(output_dir, tuning_config.outputs.csv_delimiter)
+
+
+def lint_templates(input_dir):
+    """
+    Logs the lint warnings of every template.json under input_dir.
+    Returns the number of warnings.
+    """
+    warnings_count = 0
+    for template_path in sorted(Path(input_dir).rglob(constants.TEMPLATE_FILENAME)):
+        lint_warnings = Template(
+            template_path, CONFIG_DEFAULTS, validate_bounds=False
+        ).lint()
+        for category, message in lint_warnings:
+            logger.warning(f"'{template_path}' [{category}]: {message}")
+        if len(lint_warnings) == 0:
+            logger.info(f"No lint warnings for '{template_path}'")
+        warnings_count += len(lint_warnings)
+    return warnings_count
+
+
+def score_results_file(results_path, input_dir, args):
+    """
+    Scores the responses saved in a results csv of an earlier run against the
+        evaluation.json of the input directory, without reading the images again.
+    Returns the path of the csv written with the new scores.
+    """
+    tuning_config = CONFIG_DEFAULTS
+    local_config_path = input_dir.joinpath(constants.CONFIG_FILENAME)
+    if os.path.exists(local_config_path):
+        tuning_config = open_config_with_defaults(local_config_path)
+    tuning_config = apply_config_overrides(tuning_config, args)
+
+    template_path = input_dir.joinpath(constants.TEMPLATE_FILENAME)
+    if not os.path.exists(template_path):
+        raise TemplateNotFoundError(f"No template file found at '{template_path}'")
+    template = Template(template_path, tuning_config)
+    evaluation_path = input_dir.joinpath(constants.EVALUATION_FILENAME)
+    if not os.path.exists(evaluation_path):
+        raise EvaluationInvalidError(
+            f"No evaluation file found at '{evaluation_path}'"
         )
+    evaluation_config = EvaluationConfig(
+        input_dir, evaluation_path, template, tuning_config
+    )
+
+    paths = Paths(Path(args["output_dir"]))
+    for output_dir in [paths.results_dir, paths.evaluation_dir]:
+        os.makedirs(output_dir, exist_ok=True)
+    csv_delimiter = tuning_config.outputs.csv_delimiter
+    results = pd.read_csv(
+        results_path, dtype=str, keep_default_na=False, sep=csv_delimiter
+    )
+    scores = []
+    for _, row in results.iterrows():
+        omr_response = {column: row[column] for column in template.output_columns}
+        scores.append(
+            evaluate_concatenated_response(
+                omr_response,
+                evaluation_config,
+                Path(row["input_path"]),
+                paths.evaluation_dir,
+            )
+        )
+    results["score"] = scores
+
+    rescored_path = paths.results_dir.joinpath(
+        f"{Path(results_path).stem}_rescored.csv"
+    )
+    logger.info(f"Saving {len(scores)} new scores to '{rescored_path}'")
+    results.to_csv(
+        rescored_path, quoting=QUOTE_NONNUMERIC, index=False, sep=csv_delimiter
+    )
+    return rescored_path
+
+
+def get_path_sort_key(path):
+    # Case-insensitive, so that the results rows keep the same order on every platform
+    return (str(path).lower(), str(path))
+
+
+def get_omr_files(curr_dir):
+    exts = (
+        "*.[pP][nN][gG]",
+        "*.[jJ][pP][gG]",
+        "*.[jJ][pP][eE][gG]",
+        "*.[tT][iI][fF]",
+        "*.[tT][iI][fF][fF]",
+    )
+    return sorted(
+        (f for ext in exts for f in curr_dir.glob(ext)), key=get_path_sort_key
+    )
+
+
+def get_output_dir(root_dir, curr_dir, args, tuning_config):
+    if tuning_config.outputs.preserve_input_structure:
+        # Keeps the outputs of different input directories apart
+        return Path(args["output_dir"], root_dir.name, curr_dir.relative_to(root_dir))
+    return Path(args["output_dir"], curr_dir.relative_to(root_dir))
+
+
+def write_draft_template(file_path, paths, tuning_config):
+    image = ImageUtils.read_image_grayscale(
+        str(file_path), tuning_config.inputs.preserve_bit_depth
+    )
+    draft_template = generate_draft_template(image, tuning_config.auto_layout)
+
+    os.makedirs(paths.output_dir, exist_ok=True)
+    draft_template_path = paths.output_dir.joinpath(constants.TEMPLATE_FILENAME)
+    with open(draft_template_path, "w") as f:
+        json.dump(draft_template, f, indent=4)
+    logger.info(
+        f"Saved a draft template with {len(draft_template['fieldBlocks'])} field block(s) detected from '{file_path}' to '{draft_template_path}'. Please review it before use."
+    )
 
 
 def show_template_layouts(omr_files, template, tuning_config):
     for file_path in omr_files:
         file_name = file_path.name
         file_path = str(file_path)
-        in_omr = cv2.imread(file_path, cv2.IMREAD_GRAYSCALE)
+        in_omr = ImageUtils.read_image_grayscale(
+            file_path, tuning_config.inputs.preserve_bit_depth
+        )
         in_omr = template.image_instance_ops.apply_preprocessors(
             file_path, in_omr, template
         )
@@ -196,145 +540,530 @@ def show_template_layouts(omr_files, template, tuning_config):
         )
 
 
+def process_image(image, template, file_path, save_dir=None, stage_timings=None):
+    """
+    Detects the responses of an already loaded grayscale image. Nothing is read
+        from or written to the disk unless a save_dir is given for the outputs.
+    The seconds spent per stage are added to stage_timings when one is given.
+    Returns None when the image resolution is too low or the pre-processors
+        could not make out the page.
+    """
+    image_instance_ops = template.image_instance_ops
+    inputs_config = image_instance_ops.tuning_config.inputs
+    if image_instance_ops.is_below_min_resolution(image):
+        # Upscaling a thumbnail would only give garbage detections
+        logger.error(
+            f"Image resolution {image.shape[1]}x{image.shape[0]} of '{file_path}' is too low for processing"
+        )
+        return None
+    aspect_deviation = image_instance_ops.get_aspect_deviation(image, template)
+    if aspect_deviation > inputs_config.max_aspect_deviation:
+        logger.warning(
+            f"Aspect ratio of '{file_path}' differs by {round(aspect_deviation * 100)}% from the template page"
+        )
+
+    image_instance_ops.reset_all_save_img()
+    image_instance_ops.append_save_img(1, image)
+
+    with time_stage(stage_timings, "preprocess"):
+        image = image_instance_ops.apply_preprocessors(file_path, image, template)
+    if image is None:
+        return None
+
+    file_name = Path(file_path).name
+    with time_stage(stage_timings, "detect"):
+        (
+            response_dict,
+            final_marked,
+            multi_marked,
+            _,
+            omr_metrics,
+        ) = image_instance_ops.read_omr_response(
+            template,
+            image=image,
+            name=file_name,
+            save_dir=save_dir,
+            stage_timings=stage_timings,
+        )
+    if stage_timings is not None:
+        # The field block alignment is timed within the detection
+        stage_timings["detect"] = round(
+            stage_timings["detect"] - stage_timings["align"], 4
+        )
+
+    alignment_params = image_instance_ops.tuning_config.alignment_params
+    omr_metrics["alignment_path"] = (
+        "aligned" if alignment_params.auto_align else "fast"
+    )
+    if (
+        not alignment_params.auto_align
+        and omr_metrics["file_confidence"] < alignment_params.retry_below_confidence
+    ):
+        logger.info(
+            f"Low confidence {omr_metrics['file_confidence']} for '{file_name}',"
+            " retrying with alignment"
+        )
+        with time_stage(stage_timings, "align_retry"):
+            retry_response = image_instance_ops.read_omr_response(
+                template, image=image, name=file_name, save_dir=None, auto_align=True
+            )
+        retry_metrics = retry_response[4]
+        if retry_metrics["file_confidence"] > omr_metrics["file_confidence"]:
+            response_dict, final_marked, multi_marked, multi_roll, omr_metrics = (
+                retry_response
+            )
+            omr_metrics["alignment_path"] = "aligned_retry"
+            save_aligned_detection(
+                image_instance_ops.tuning_config,
+                final_marked,
+                file_name,
+                multi_roll,
+                save_dir,
+            )
+
+    omr_metrics["student_id"] = (
+        image_instance_ops.read_barcode(template, image)
+        if template.barcode_region is not None
+        else None
+    )
+
+    # TODO: move inner try catch here
+    # concatenate roll nos, set unmarked responses, etc
+    omr_response = get_concatenated_response(response_dict, template)
+    invalid_dates = get_invalid_dates(response_dict, template)
+    if len(invalid_dates) > 0:
+        logger.warning(f"Impossible dates {invalid_dates} in '{file_name}'")
+        omr_metrics["needs_review"].extend(invalid_dates)
+    return omr_response, final_marked, multi_marked, omr_metrics
+
+
+def process_image_regions(
+    image, template, file_path, save_dir=None, stage_timings=None
+):
+    """
+    Detects each student region of a multi-student sheet as a separate result.
+    Returns (file id, detection) pairs, a single one for a template without regions.
+    """
+    file_path = Path(file_path)
+    if len(template.student_region_templates) == 0:
+        return [
+            (
+                file_path.name,
+                process_image(
+                    image,
+                    template,
+                    file_path,
+                    save_dir=save_dir,
+                    stage_timings=stage_timings,
+                ),
+            )
+        ]
+    region_detections = []
+    for region_index, region_template in enumerate(
+        template.student_region_templates, start=1
+    ):
+        # Tags the results and the saved detections with the region index
+        file_id = f"{file_path.stem}_region{region_index}{file_path.suffix}"
+        # The stage timings would add up over the regions, only the load is timed
+        region_detections.append(
+            (
+                file_id,
+                process_image(
+                    image,
+                    region_template,
+                    file_path.with_name(file_id),
+                    save_dir=save_dir,
+                ),
+            )
+        )
+    return region_detections
+
+
+def save_aligned_detection(
+    tuning_config, final_marked, file_name, multi_roll, save_dir
+):
+    # Replaces the detection image saved by the fast path
+    if not tuning_config.outputs.save_detections or save_dir is None:
+        return
+    if multi_roll:
+        save_dir = save_dir.joinpath("_MULTI_")
+    ImageUtils.save_img(str(save_dir.joinpath(file_name)), final_marked)
+
+
 def process_files(
     omr_files,
     template,
     tuning_config,
     evaluation_config,
     outputs_namespace,
+    corrections=None,
 ):
     start_time = int(time())
     files_counter = 0
     STATS.files_not_moved = 0
+    STATS.fields_corrected = 0
+    corrections = corrections or {}
+    output_format = tuning_config.outputs.output_format
+    write_files, write_ndjson = (
+        output_format != "ndjson",
+        output_format != "files",
+    )
+    if write_files:
+        mark_in_progress(outputs_namespace.paths)
+    # A live bar would garble the ndjson records on stdout
+    run_progress = RunProgress(len(omr_files), live=not write_ndjson)
+    records_count = count_records(outputs_namespace)
+
+    def move_error_file(file_id, file_path):
+        new_file_path = outputs_namespace.paths.errors_dir.joinpath(file_id)
+        outputs_namespace.OUTPUT_SET.append(
+            [file_id] + outputs_namespace.empty_resp + [""]
+        )
+        if write_ndjson:
+            emit_ndjson_record(
+                {
+                    "file_id": file_id,
+                    "input_path": str(file_path),
+                    "status": "error",
+                }
+            )
+        if write_files and check_and_move(
+            constants.ERROR_CODES.NO_MARKER_ERR, file_path, new_file_path
+        ):
+            err_line = [
+                file_id,
+                file_path,
+                new_file_path,
+                "NA",
+            ] + outputs_namespace.empty_resp + outputs_namespace.empty_metrics
+            append_results_row(outputs_namespace, "Errors", err_line)
+
+    def complete_file(file_path):
+        nonlocal records_count
+        if write_files:
+            # Saves what the file added to the aggregates, for --resume to restore
+            append_progress(
+                outputs_namespace.paths,
+                file_path,
+                get_added_records(outputs_namespace, records_count),
+            )
+            records_count = count_records(outputs_namespace)
+        run_progress.advance()
 
     for file_path in omr_files:
+        if files_counter > 0:
+            # Only reached once the previous file is fully written out
+            complete_file(omr_files[files_counter - 1])
         files_counter += 1
         file_name = file_path.name
+        set_current_file(None)
+        # Only measured when debugging
+        stage_timings = {} if is_debug_enabled() else None
 
-        in_omr = cv2.imread(str(file_path), cv2.IMREAD_GRAYSCALE)
+        try:
+            with time_stage(stage_timings, "load"):
+                in_omr = ImageUtils.read_image_with_retry(
+                    str(file_path),
+                    tuning_config.inputs.preserve_bit_depth,
+                    tuning_config.inputs.read_timeout_seconds,
+                    tuning_config.inputs.read_retries,
+                )
+            if in_omr is None:
+                raise ImageReadError(f"Unable to read image: '{file_path}'")
+        except ImageReadError as error:
+            # A timed out or corrupt file goes to the errors, the batch goes on
+            logger.error(f"[{files_counter}] {error}")
+            move_error_file(file_name, file_path)
+            continue
 
         logger.info("")
         logger.info(
             f"({files_counter}) Opening image: \t'{file_path}'\tResolution: {in_omr.shape}"
         )
+        set_current_file(file_name)
 
-        template.image_instance_ops.reset_all_save_img()
+        save_dir = outputs_namespace.paths.save_marked_dir
+        region_detections = process_image_regions(
+            in_omr, template, file_path, save_dir=save_dir, stage_timings=stage_timings
+        )
+        if stage_timings is not None:
+            logger.debug(f"Stage timings (seconds): {stage_timings}")
+        thumbnail = (
+            ImageUtils.get_thumbnail_base64(
+                in_omr, tuning_config.outputs.thumbnail_max_dim
+            )
+            if write_ndjson and tuning_config.outputs.embed_thumbnails
+            else None
+        )
 
-        template.image_instance_ops.append_save_img(1, in_omr)
+        # A sheet shared by several students gives one result per student region
+        for file_id, detection in region_detections:
+            if detection is None:
+                # Error OMR case
+                move_error_file(file_id, file_path)
+                continue
 
-        in_omr = template.image_instance_ops.apply_preprocessors(
-            file_path, in_omr, template
-        )
+            omr_response, final_marked, multi_marked, omr_metrics = detection
+            omr_metrics["manually_corrected"] = apply_corrections(
+                omr_response, corrections.get(file_id, {}), file_id
+            )
+            if len(omr_metrics["manually_corrected"]) > 0:
+                logger.info(
+                    f"Manually corrected {omr_metrics['manually_corrected']} for '{file_id}'"
+                )
+                STATS.fields_corrected += len(omr_metrics["manually_corrected"])
 
-        if in_omr is None:
-            # Error OMR case
-            new_file_path = outputs_namespace.paths.errors_dir.joinpath(file_name)
-            outputs_namespace.OUTPUT_SET.append(
-                [file_name] + outputs_namespace.empty_resp
+            reject_below_confidence = tuning_config.outputs.reject_below_confidence
+            if omr_metrics["file_confidence"] < reject_below_confidence:
+                # A whole-sheet gate, such sheets are re-scanned instead of scored
+                reason = f"file confidence {omr_metrics['file_confidence']} is below {reject_below_confidence}"
+                logger.warning(f"[{files_counter}] Rejected '{file_id}': {reason}")
+                outputs_namespace.rejected_files.append((file_id, reason))
+                if write_ndjson:
+                    emit_ndjson_record(
+                        {
+                            "file_id": file_id,
+                            "input_path": str(file_path),
+                            "status": "rejected",
+                            "file_confidence": omr_metrics["file_confidence"],
+                            "reason": reason,
+                        }
+                    )
+                if write_files:
+                    rejected_dir = outputs_namespace.paths.rejected_dir
+                    os.makedirs(rejected_dir, exist_ok=True)
+                    new_file_path = rejected_dir.joinpath(file_id)
+                    shutil.copy(file_path, new_file_path)
+                    append_results_row(
+                        outputs_namespace,
+                        "Rejected",
+                        [file_id, file_path, new_file_path, "NA"]
+                        + [omr_response[k] for k in template.output_columns]
+                        + get_metrics_row(omr_metrics, multi_marked),
+                    )
+                continue
+
+            file_evaluation_config = (
+                evaluation_config.get_config_for_response(omr_response)
+                if evaluation_config is not None
+                else None
             )
-            if check_and_move(
-                constants.ERROR_CODES.NO_MARKER_ERR, file_path, new_file_path
+            if (
+                file_evaluation_config is None
+                or not file_evaluation_config.get_should_explain_scoring()
             ):
-                err_line = [
-                    file_name,
+                logger.info(f"Read Response: \n{omr_response}")
+
+            score, grade, omitted_questions = 0, None, []
+            if evaluation_config is not None and file_evaluation_config is None:
+                version_field = evaluation_config.version_field
+                logger.warning(
+                    f"[{files_counter}] Not scoring '{file_id}', its {version_field} '{omr_response[version_field]}' has no answer key"
+                )
+                omr_metrics["needs_review"].append(version_field)
+                score = "NA"
+            elif file_evaluation_config is not None:
+                score = evaluate_concatenated_response(
+                    omr_response,
+                    file_evaluation_config,
                     file_path,
-                    new_file_path,
-                    "NA",
-                ] + outputs_namespace.empty_resp
-                pd.DataFrame(err_line, dtype=str).T.to_csv(
-                    outputs_namespace.files_obj["Errors"],
-                    mode="a",
-                    quoting=QUOTE_NONNUMERIC,
-                    header=False,
-                    index=False,
+                    outputs_namespace.paths.evaluation_dir,
+                    omr_metrics,
                 )
-            continue
-
-        # uniquify
-        file_id = str(file_name)
-        save_dir = outputs_namespace.paths.save_marked_dir
-        (
-            response_dict,
-            final_marked,
-            multi_marked,
-            _,
-        ) = template.image_instance_ops.read_omr_response(
-            template, image=in_omr, name=file_id, save_dir=save_dir
-        )
+                logger.info(
+                    f"(/{files_counter}) Graded with score: {round(score, 2)}\t for file: '{file_id}'"
+                )
+                if file_evaluation_config.has_non_default_section:
+                    logger.info(
+                        f"Section scores: {file_evaluation_config.get_section_scores()}"
+                    )
+                outputs_namespace.evaluation_records.append(
+                    (file_evaluation_config.get_question_verdicts(), score)
+                )
+                omitted_questions = file_evaluation_config.get_omitted_questions()
+                if len(omitted_questions) > 0:
+                    logger.info(
+                        f"(/{files_counter}) Left blank: {omitted_questions}\t for file: '{file_id}'"
+                    )
+                for question in omitted_questions:
+                    outputs_namespace.omitted_counts[question] = (
+                        outputs_namespace.omitted_counts.get(question, 0) + 1
+                    )
+                # A decoded barcode is more reliable than the bubbled roll number
+                roll_number_field = file_evaluation_config.roll_number_field
+                student_id = omr_metrics["student_id"]
+                if student_id is None and roll_number_field is not None:
+                    student_id = omr_response[roll_number_field]
+                if student_id is not None:
+                    outputs_namespace.gradebook_records.append(
+                        (
+                            student_id,
+                            file_evaluation_config.get_question_marks(),
+                            score,
+                        )
+                    )
+                grade = file_evaluation_config.get_grade(score)
+                if grade is not None:
+                    logger.info(
+                        f"(/{files_counter}) Grade: {grade}\t for file: '{file_id}'"
+                    )
+            else:
+                logger.info(f"(/{files_counter}) Processed file: '{file_id}'")
 
-        # TODO: move inner try catch here
-        # concatenate roll nos, set unmarked responses, etc
-        omr_response = get_concatenated_response(response_dict, template)
+            if tuning_config.outputs.show_image_level >= 2:
+                InteractionUtils.show(
+                    f"Final Marked Bubbles : '{file_id}'",
+                    ImageUtils.resize_util_h(
+                        final_marked,
+                        int(tuning_config.dimensions.display_height * 1.3),
+                    ),
+                    1,
+                    1,
+                    config=tuning_config,
+                )
 
-        if (
-            evaluation_config is None
-            or not evaluation_config.get_should_explain_scoring()
-        ):
-            logger.info(f"Read Response: \n{omr_response}")
+            resp_array = []
+            for k in template.output_columns:
+                resp_array.append(omr_response[k])
 
-        score = 0
-        if evaluation_config is not None:
-            score = evaluate_concatenated_response(
-                omr_response, evaluation_config, file_path, outputs_namespace.paths.evaluation_dir
+            file_confidence, is_blank = map(
+                omr_metrics.get, ["file_confidence", "is_blank"]
             )
-            logger.info(
-                f"(/{files_counter}) Graded with score: {round(score, 2)}\t for file: '{file_id}'"
-            )
-        else:
-            logger.info(f"(/{files_counter}) Processed file: '{file_id}'")
+            if is_blank:
+                logger.warning(
+                    f"[{files_counter}] Found a blank sheet with ink ratio {omr_metrics['ink_ratio']}: '{file_id}'"
+                )
+            metrics_array = get_metrics_row(omr_metrics, multi_marked)
 
-        if tuning_config.outputs.show_image_level >= 2:
-            InteractionUtils.show(
-                f"Final Marked Bubbles : '{file_id}'",
-                ImageUtils.resize_util_h(
-                    final_marked, int(tuning_config.dimensions.display_height * 1.3)
-                ),
-                1,
-                1,
-                config=tuning_config,
+            outputs_namespace.OUTPUT_SET.append(
+                [file_id] + resp_array + [file_confidence]
             )
+            outputs_namespace.marked_bubbles.append(omr_metrics["marked_bubbles"])
+            for field_label, confidence in omr_metrics["field_confidences"].items():
+                outputs_namespace.field_confidences.setdefault(field_label, []).append(
+                    confidence
+                )
 
-        resp_array = []
-        for k in template.output_columns:
-            resp_array.append(omr_response[k])
-
-        outputs_namespace.OUTPUT_SET.append([file_name] + resp_array)
-
-        if multi_marked == 0 or not tuning_config.outputs.filter_out_multimarked_files:
-            STATS.files_not_moved += 1
-            new_file_path = save_dir.joinpath(file_id)
-            # Enter into Results sheet-
-            results_line = [file_name, file_path, new_file_path, score] + resp_array
-            # Write/Append to results_line file(opened in append mode)
-            pd.DataFrame(results_line, dtype=str).T.to_csv(
-                outputs_namespace.files_obj["Results"],
-                mode="a",
-                quoting=QUOTE_NONNUMERIC,
-                header=False,
-                index=False,
-            )
-        else:
-            # multi_marked file
-            logger.info(f"[{files_counter}] Found multi-marked file: '{file_id}'")
-            new_file_path = outputs_namespace.paths.multi_marked_dir.joinpath(file_name)
-            if check_and_move(
-                constants.ERROR_CODES.MULTI_BUBBLE_WARN, file_path, new_file_path
+            if write_ndjson:
+                emit_ndjson_record(
+                    {
+                        "file_id": file_id,
+                        "input_path": str(file_path),
+                        "status": "multi_marked" if multi_marked else "ok",
+                        "score": score,
+                        "grade": grade,
+                        "omitted_questions": omitted_questions,
+                        "response": dict(zip(template.output_columns, resp_array)),
+                        "file_confidence": file_confidence,
+                        "multi_marked": bool(multi_marked),
+                        "is_blank": is_blank,
+                        "blur_score": omr_metrics["blur_score"],
+                        "contrast_score": omr_metrics["contrast_score"],
+                        "needs_review": omr_metrics["needs_review"],
+                        "manually_corrected": omr_metrics["manually_corrected"],
+                        "alignment_path": omr_metrics["alignment_path"],
+                        "student_id": omr_metrics["student_id"],
+                        "thumbnail": thumbnail,
+                        **(
+                            {"stage_timings": stage_timings}
+                            if stage_timings is not None
+                            else {}
+                        ),
+                    }
+                )
+            if not write_files:
+                continue
+
+            if (
+                multi_marked == 0
+                or not tuning_config.outputs.filter_out_multimarked_files
             ):
-                mm_line = [file_name, file_path, new_file_path, "NA"] + resp_array
-                pd.DataFrame(mm_line, dtype=str).T.to_csv(
-                    outputs_namespace.files_obj["MultiMarked"],
-                    mode="a",
-                    quoting=QUOTE_NONNUMERIC,
-                    header=False,
-                    index=False,
+                STATS.files_not_moved += 1
+                new_file_path = save_dir.joinpath(file_id)
+                # Enter into Results sheet-
+                results_line = (
+                    [file_id, file_path, new_file_path, score]
+                    + resp_array
+                    + metrics_array
+                )
+                # Write/Append to results_line file(opened in append mode)
+                append_results_row(outputs_namespace, "Results", results_line)
+            else:
+                # multi_marked file
+                logger.info(f"[{files_counter}] Found multi-marked file: '{file_id}'")
+                new_file_path = outputs_namespace.paths.multi_marked_dir.joinpath(
+                    file_id
                 )
-            # else:
-            #     TODO:  Add appropriate record handling here
-            #     pass
+                if check_and_move(
+                    constants.ERROR_CODES.MULTI_BUBBLE_WARN, file_path, new_file_path
+                ):
+                    mm_line = (
+                        [file_id, file_path, new_file_path, "NA"]
+                        + resp_array
+                        + metrics_array
+                    )
+                    append_results_row(outputs_namespace, "MultiMarked", mm_line)
+                # else:
+                #     TODO:  Add appropriate record handling here
+                #     pass
 
+    if files_counter > 0:
+        complete_file(omr_files[files_counter - 1])
+    run_progress.stop()
+    if write_files and tuning_config.outputs.save_xlsx:
+        write_results_xlsx(outputs_namespace, template)
+    if write_files:
+        write_confidence_histogram(outputs_namespace)
+    if write_files and tuning_config.outputs.save_mark_heatmap:
+        write_mark_heatmap(outputs_namespace, template)
+    if write_files and len(outputs_namespace.evaluation_records) > 0:
+        write_item_analysis(
+            outputs_namespace, get_item_analysis(outputs_namespace.evaluation_records)
+        )
+    if write_files and len(outputs_namespace.gradebook_records) > 0:
+        write_gradebook(
+            outputs_namespace,
+            build_gradebook(
+                outputs_namespace.gradebook_records,
+                evaluation_config.questions_in_order,
+            ),
+        )
+
+    if write_files:
+        write_outputs_manifest(outputs_namespace, template, tuning_config)
+        clear_in_progress(outputs_namespace.paths)
+    set_current_file(None)
+    if len(outputs_namespace.omitted_counts) > 0:
+        logger.info(
+            f"Questions left blank (number of sheets): {outputs_namespace.omitted_counts}"
+        )
+    if len(outputs_namespace.rejected_files) > 0:
+        logger.warning(
+            f"Rejected {len(outputs_namespace.rejected_files)} file(s) for re-scan: {[file_id for file_id, _ in outputs_namespace.rejected_files]}"
+        )
     print_stats(start_time, files_counter, tuning_config)
 
 
+def get_metrics_row(omr_metrics, multi_marked):
+    # The metrics columns of a results csv row, see metricsCols
+    return [
+        omr_metrics["file_confidence"],
+        bool(multi_marked),
+        omr_metrics["is_blank"],
+        ",".join(omr_metrics["needs_review"]),
+        omr_metrics["blur_score"],
+        omr_metrics["contrast_score"],
+        ",".join(omr_metrics["manually_corrected"]),
+    ]
+
+
+def emit_ndjson_record(record):
+    # One JSON object per line, flushed so that callers can consume it right away
+    # Numpy scalars in the scores and confidences are converted with item()
+    sys.stdout.write(json.dumps(record, default=lambda value: value.item()) + "\n")
+    sys.stdout.flush()
+
+
 def check_and_move(error_code, file_path, filepath2):
     # TODO: fix file movement into error/multimarked/invalid etc again
     STATS.files_not_moved += 1
@@ -347,6 +1076,8 @@ def print_stats(start_time, files_counter, tuning_config):
     log("")
     log(f"{'Total file(s) moved': <27}: {STATS.files_moved}")
     log(f"{'Total file(s) not moved': <27}: {STATS.files_not_moved}")
+    if STATS.fields_corrected > 0:
+        log(f"{'Total field(s) corrected': <27}: {STATS.fields_corrected}")
     log("--------------------------------")
     log(
         f"{'Total file(s) processed': <27}: {files_counter} ({'Sum Tallied!' if files_counter == (STATS.files_moved + STATS.files_not_moved) else 'Not Tallying!'})"
@@ -5,15 +5,18 @@ from copy import deepcopy
 from csv import QUOTE_NONNUMERIC
 
 import cv2
+import numpy as np
 import pandas as pd
 from rich.table import Table
 
+from src.exceptions import EvaluationInvalidError
 from src.logger import console, logger
 from src.schemas.constants import (
     BONUS_SECTION_PREFIX,
     DEFAULT_SECTION_KEY,
     MARKING_VERDICT_TYPES,
 )
+from src.utils.feedback import write_feedback_pdf
 from src.utils.parsing import (
     get_concatenated_response,
     open_evaluation_with_validation,
@@ -70,7 +73,7 @@ class AnswerMatcher:
         logger.critical(
             f"Unable to determine answer type for answer item: {answer_item}"
         )
-        raise Exception("Unable to determine answer type")
+        raise EvaluationInvalidError("Unable to determine answer type")
 
     def set_defaults_from_scheme(self, section_marking_scheme):
         answer_type = self.answer_type
@@ -155,9 +158,12 @@ class SectionMarkingScheme:
         if section_key == DEFAULT_SECTION_KEY:
             self.questions = None
             self.marking = self.parse_scheme_marking(section_scheme)
+            self.weight, self.floor = 1, None
         else:
             self.questions = parse_fields(section_key, section_scheme["questions"])
             self.marking = self.parse_scheme_marking(section_scheme["marking"])
+            self.weight = section_scheme.get("weight", 1)
+            self.floor = section_scheme.get("floor", None)
 
     def __str__(self):
         return self.section_key
@@ -196,11 +202,18 @@ class EvaluationConfig:
             evaluation_json.get, ["options", "marking_schemes", "source_type"]
         )
         self.should_explain_scoring = options.get("should_explain_scoring", False)
+        self.multi_marked_policy = options.get("multi_marked_policy", "incorrect")
+        self.grade_bands = sorted(
+            options.get("grade_bands", []), key=lambda band: band[0], reverse=True
+        )
         self.has_non_default_section = False
         self.exclude_files = []
         self.enable_evaluation_table_to_csv = options.get(
             "enable_evaluation_table_to_csv", False
         )
+        self.enable_feedback_pdf = options.get("enable_feedback_pdf", False)
+        self.roll_number_field = options.get("roll_number_field", None)
+        self.version_field = options.get("version_field", "version")
 
         if source_type == "csv":
             csv_path = curr_dir.joinpath(options["answer_key_csv_path"])
@@ -220,11 +233,15 @@ class EvaluationConfig:
                 self.questions_in_order = answer_key["question"].to_list()
                 answers_in_order = answer_key["answer"].to_list()
             elif not answer_key_image_path:
-                raise Exception(f"Answer key csv not found at '{csv_path}'")
+                raise EvaluationInvalidError(
+                    f"Answer key csv not found at '{csv_path}'"
+                )
             else:
                 image_path = str(curr_dir.joinpath(answer_key_image_path))
                 if not os.path.exists(image_path):
-                    raise Exception(f"Answer key image not found at '{image_path}'")
+                    raise EvaluationInvalidError(
+                        f"Answer key image not found at '{image_path}'"
+                    )
 
                 # self.exclude_files.append(image_path)
 
@@ -237,7 +254,7 @@ class EvaluationConfig:
                     image_path, in_omr, template
                 )
                 if in_omr is None:
-                    raise Exception(
+                    raise EvaluationInvalidError(
                         f"Could not read answer key from image {image_path}"
                     )
                 (
@@ -245,6 +262,7 @@ class EvaluationConfig:
                     _final_marked,
                     _multi_marked,
                     _multi_roll,
+                    _omr_metrics,
                 ) = template.image_instance_ops.read_omr_response(
                     template,
                     image=in_omr,
@@ -271,7 +289,7 @@ class EvaluationConfig:
                         logger.error(
                             f"Found empty answers for questions: {empty_answered_questions}, empty value used: '{empty_val}'"
                         )
-                        raise Exception(
+                        raise EvaluationInvalidError(
                             f"Found empty answers in file '{image_path}'. Please check your template again in the --setLayout mode."
                         )
                 else:
@@ -293,6 +311,27 @@ class EvaluationConfig:
             )
             answers_in_order = options["answers_in_order"]
 
+        # Manual fields like a signature box are captured but never scored
+        manual_questions = [
+            question
+            for question in self.questions_in_order
+            if question in template.manual_field_labels
+        ]
+        if len(manual_questions) > 0:
+            logger.warning(
+                f"Not scoring the manual fields in the answer key: {manual_questions}"
+            )
+            answers_in_order = [
+                answer
+                for question, answer in zip(self.questions_in_order, answers_in_order)
+                if question not in manual_questions
+            ]
+            self.questions_in_order = [
+                question
+                for question in self.questions_in_order
+                if question not in manual_questions
+            ]
+
         self.validate_questions(answers_in_order)
 
         self.section_marking_schemes, self.question_to_scheme = {}, {}
@@ -322,13 +361,18 @@ class EvaluationConfig:
     # Externally called methods have higher abstraction level.
     def prepare_and_validate_omr_response(self, omr_response):
         self.reset_explanation_table()
+        self.section_scores = {}
+        # (question, marked_answer, answer, verdict, delta) for the current sheet
+        self.question_results = []
+        # Questions left entirely blank on the current sheet, unlike wrong answers
+        self.omitted_questions = []
 
         omr_response_questions = set(omr_response.keys())
         all_questions = set(self.questions_in_order)
         missing_questions = sorted(all_questions.difference(omr_response_questions))
         if len(missing_questions) > 0:
             logger.critical(f"Missing OMR response for: {missing_questions}")
-            raise Exception(
+            raise EvaluationInvalidError(
                 f"Some questions are missing in the OMR response for the given answer key"
             )
 
@@ -346,6 +390,10 @@ class EvaluationConfig:
     def match_answer_for_question(self, current_score, question, marked_answer):
         answer_matcher = self.question_to_answer_matcher[question]
         question_verdict, delta = answer_matcher.get_verdict_marking(marked_answer)
+        delta *= self.get_marking_scheme_for_question(question).weight
+        self.question_results.append(
+            (question, marked_answer, str(answer_matcher), question_verdict, delta)
+        )
         self.conditionally_add_explanation(
             answer_matcher,
             delta,
@@ -356,6 +404,65 @@ class EvaluationConfig:
         )
         return delta
 
+    def resolve_multi_marked_answer(self, question, marked_answer, marked_values):
+        """
+        "incorrect": the concatenated marks are matched as is (i.e. usually incorrect)
+        "darkest": only the darkest mark is considered, this needs the marked
+            values of the field ordered by intensity from the detection
+        "any_correct": correct if any one of the marks is a correct answer
+        "as_blank": the question is treated as unmarked
+        """
+        answer_matcher = self.question_to_answer_matcher[question]
+        policy = self.multi_marked_policy
+        if policy == "as_blank":
+            return answer_matcher.empty_val
+        if policy == "darkest":
+            return marked_values[0]
+        if policy == "any_correct":
+            for marked_value in marked_values:
+                question_verdict, _ = answer_matcher.get_verdict_marking(marked_value)
+                if question_verdict.startswith("correct"):
+                    return marked_value
+        return marked_answer
+
+    def get_clamped_section_scores(self, section_scores):
+        clamped_section_scores = {}
+        for section_key, section_score in section_scores.items():
+            section_floor = self.section_marking_schemes.get(
+                section_key, self.default_marking_scheme
+            ).floor
+            if section_floor is not None and section_score < section_floor:
+                logger.info(
+                    f"Clamping the score {round(section_score, 2)} of section '{section_key}' to its floor {section_floor}"
+                )
+                section_score = section_floor
+            clamped_section_scores[section_key] = section_score
+        return clamped_section_scores
+
+    def get_section_scores(self):
+        return self.section_scores
+
+    def get_max_score(self):
+        max_score = 0
+        for question in self.questions_in_order:
+            answer_matcher = self.question_to_answer_matcher[question]
+            max_score += self.get_marking_scheme_for_question(question).weight * max(
+                score
+                for verdict, score in answer_matcher.marking.items()
+                if verdict.startswith("correct")
+            )
+        return max_score
+
+    def get_grade(self, score):
+        if len(self.grade_bands) == 0:
+            return None
+        max_score = self.get_max_score()
+        percentage = round(100 * score / max_score, 2) if max_score > 0 else 0
+        for lower_bound, grade in self.grade_bands:
+            if percentage >= lower_bound:
+                return grade
+        return None
+
     def conditionally_print_explanation(self):
         if self.should_explain_scoring:
             console.print(self.explanation_table, justify="center")
@@ -377,12 +484,42 @@ class EvaluationConfig:
                 index=False,
             )
 
+    def conditionally_save_feedback_pdf(self, file_path, evaluation_output_dir, score):
+        if self.enable_feedback_pdf:
+            output_path = os.path.join(
+                evaluation_output_dir,
+                f"{file_path.stem}_feedback.pdf",
+            )
+            write_feedback_pdf(
+                output_path,
+                file_path.name,
+                score,
+                self.get_max_score(),
+                self.get_grade(score),
+                self.question_results,
+            )
+
+    def get_question_verdicts(self):
+        return {
+            question: question_verdict
+            for question, _, _, question_verdict, _ in self.question_results
+        }
+
+    def get_question_marks(self):
+        return {question: delta for question, _, _, _, delta in self.question_results}
+
+    def get_omitted_questions(self):
+        return self.omitted_questions
+
     def get_should_explain_scoring(self):
         return self.should_explain_scoring
 
     def get_exclude_files(self):
         return self.exclude_files
 
+    def get_config_for_response(self, omr_response):
+        return self
+
     @staticmethod
     def parse_answer_column(answer_column):
         if answer_column[0] == "[":
@@ -419,7 +556,7 @@ class EvaluationConfig:
                             multi_marked_answer = True
 
                 if multi_marked_answer:
-                    raise Exception(
+                    raise EvaluationInvalidError(
                         f"Provided answer key contains multiple correct answer(s), but config.filter_out_multimarked_files is True. Scoring will get skipped."
                     )
 
@@ -432,7 +569,7 @@ class EvaluationConfig:
             logger.critical(
                 f"questions_in_order({len_questions_in_order}): {questions_in_order}\nanswers_in_order({len_answers_in_order}): {answers_in_order}"
             )
-            raise Exception(
+            raise EvaluationInvalidError(
                 f"Unequal lengths for questions_in_order and answers_in_order ({len_questions_in_order} != {len_answers_in_order})"
             )
 
@@ -444,7 +581,7 @@ class EvaluationConfig:
                 continue
             current_set = set(section_scheme.questions)
             if not section_questions.isdisjoint(current_set):
-                raise Exception(
+                raise EvaluationInvalidError(
                     f"Section '{section_key}' has overlapping question(s) with other sections"
                 )
             section_questions = section_questions.union(current_set)
@@ -453,7 +590,7 @@ class EvaluationConfig:
         missing_questions = sorted(section_questions.difference(all_questions))
         if len(missing_questions) > 0:
             logger.critical(f"Missing answer key for: {missing_questions}")
-            raise Exception(
+            raise EvaluationInvalidError(
                 f"Some questions are missing in the answer key for the given marking scheme"
             )
 
@@ -528,18 +665,195 @@ class EvaluationConfig:
             self.explanation_table.add_row(*row)
 
 
+class VersionedEvaluationConfig:
+    """Answer keys of the shuffled versions of an exam, e.g. A.json and B.json.
+    Each sheet is scored against the key named by its bubbled version field."""
+
+    def __init__(self, answer_key_dir, template, tuning_config):
+        self.path = answer_key_dir
+        answer_key_paths = sorted(answer_key_dir.glob("*.json"))
+        if len(answer_key_paths) == 0:
+            raise EvaluationInvalidError(
+                f"No answer keys found in the directory '{answer_key_dir}'"
+            )
+        self.versions = {
+            answer_key_path.stem: EvaluationConfig(
+                answer_key_dir, answer_key_path, template, tuning_config
+            )
+            for answer_key_path in answer_key_paths
+        }
+        first_version = next(iter(self.versions.values()))
+        self.version_field = first_version.version_field
+        if self.version_field not in template.output_columns:
+            raise EvaluationInvalidError(
+                f"Version field '{self.version_field}' is not an output column of the template"
+            )
+        # Question labels are the same across the versions, only their answers differ
+        self.questions_in_order = first_version.questions_in_order
+
+    def __str__(self):
+        return f"{self.path} (versions: {', '.join(self.versions)})"
+
+    def get_exclude_files(self):
+        return [
+            exclude_file
+            for evaluation_config in self.versions.values()
+            for exclude_file in evaluation_config.get_exclude_files()
+        ]
+
+    def get_config_for_response(self, omr_response):
+        # None for a missing or unknown version, such sheets must not be scored
+        return self.versions.get(omr_response.get(self.version_field))
+
+
+def get_item_analysis(evaluation_records):
+    """
+    evaluation_records: a (question verdicts, total score) pair for each student
+    difficulty: the fraction of students that answered the question correctly
+    discrimination: the point-biserial correlation between answering the
+        question correctly and the total score, None when either doesn't vary
+    """
+    scores = np.array([score for _, score in evaluation_records], dtype=float)
+    scores_std = scores.std()
+    item_analysis = {}
+    for question in evaluation_records[0][0].keys():
+        is_correct = np.array(
+            [
+                question_verdicts[question].startswith("correct")
+                for question_verdicts, _ in evaluation_records
+            ]
+        )
+        difficulty = float(is_correct.mean())
+        discrimination = None
+        if 0 < difficulty < 1 and scores_std > 0:
+            discrimination = round(
+                float(
+                    (scores[is_correct].mean() - scores[~is_correct].mean())
+                    / scores_std
+                    * np.sqrt(difficulty * (1 - difficulty))
+                ),
+                4,
+            )
+        item_analysis[question] = {
+            "difficulty": round(difficulty, 4),
+            "discrimination": discrimination,
+        }
+    return item_analysis
+
+
+def get_cohens_kappa(labels_a, labels_b):
+    # Agreement between two graders corrected for the agreement expected by chance
+    labels_count = len(labels_a)
+    observed = sum(a == b for a, b in zip(labels_a, labels_b)) / labels_count
+    expected = sum(
+        (labels_a.count(label) / labels_count) * (labels_b.count(label) / labels_count)
+        for label in set(labels_a) | set(labels_b)
+    )
+    if expected == 1:
+        return 1.0
+    return round((observed - expected) / (1 - expected), 4)
+
+
+def get_agreement_report(results_a, results_b, fields):
+    """
+    results_a, results_b: the responses of two graders as DataFrames with a
+        file_id column, e.g. the Results csv and a human-keyed copy of it
+    Returns the match rate of each field, Cohen's kappa over all the compared
+        values and the (file_id, field, value_a, value_b) disagreements.
+    """
+    merged = results_a.merge(results_b, on="file_id", suffixes=("_a", "_b"))
+    labels_a, labels_b, disagreements, match_rates = [], [], [], {}
+    for field in fields:
+        values_a, values_b = (
+            merged[f"{field}_a"].to_list(),
+            merged[f"{field}_b"].to_list(),
+        )
+        matches = [a == b for a, b in zip(values_a, values_b)]
+        match_rates[field] = round(sum(matches) / len(matches), 4)
+        disagreements.extend(
+            (file_id, field, a, b)
+            for file_id, a, b, is_match in zip(
+                merged["file_id"], values_a, values_b, matches
+            )
+            if not is_match
+        )
+        labels_a.extend(values_a)
+        labels_b.extend(values_b)
+    return {
+        "files_count": len(merged),
+        "match_rates": match_rates,
+        "kappa": get_cohens_kappa(labels_a, labels_b),
+        "disagreements": disagreements,
+    }
+
+
+def build_gradebook(gradebook_records, questions_in_order):
+    """
+    gradebook_records: a (roll number, question marks, total score) triple for each student
+    Returns one row per roll number with the marks of each question and the total.
+    """
+    rows, seen_roll_numbers = {}, {}
+    for roll_number, question_marks, score in gradebook_records:
+        seen_count = seen_roll_numbers.get(roll_number, 0) + 1
+        seen_roll_numbers[roll_number] = seen_count
+        row_key = roll_number
+        if seen_count > 1:
+            row_key = f"{roll_number}_{seen_count}"
+            logger.warning(
+                f"Found duplicate roll number '{roll_number}', saving it as '{row_key}' in the gradebook"
+            )
+        rows[row_key] = [question_marks[question] for question in questions_in_order]
+        rows[row_key].append(score)
+
+    return pd.DataFrame.from_dict(
+        rows, orient="index", columns=questions_in_order + ["total"]
+    ).rename_axis("roll_number")
+
+
 def evaluate_concatenated_response(
-    concatenated_response, evaluation_config, file_path, evaluation_output_dir
+    concatenated_response,
+    evaluation_config,
+    file_path,
+    evaluation_output_dir,
+    omr_metrics=None,
 ):
     evaluation_config.prepare_and_validate_omr_response(concatenated_response)
+    # Note: custom labels are concatenated, so only the plain fields are resolved
+    multi_marked_values = (
+        omr_metrics["multi_marked_values"] if omr_metrics is not None else {}
+    )
     current_score = 0.0
+    section_scores = {}
     for question in evaluation_config.questions_in_order:
         marked_answer = concatenated_response[question]
+        # Checked before the multi-marked policy, which may also blank an answer
+        if (
+            marked_answer
+            == evaluation_config.question_to_answer_matcher[question].empty_val
+        ):
+            evaluation_config.omitted_questions.append(question)
+        if question in multi_marked_values:
+            marked_answer = evaluation_config.resolve_multi_marked_answer(
+                question, marked_answer, multi_marked_values[question]
+            )
         delta = evaluation_config.match_answer_for_question(
             current_score, question, marked_answer
         )
+        section_key = evaluation_config.get_marking_scheme_for_question(
+            question
+        ).section_key
+        section_scores[section_key] = section_scores.get(section_key, 0.0) + delta
         current_score += delta
 
+    # The total is the sum of the section scores clamped at their floors
+    evaluation_config.section_scores = evaluation_config.get_clamped_section_scores(
+        section_scores
+    )
+    current_score = sum(evaluation_config.section_scores.values())
+    evaluation_config.conditionally_save_feedback_pdf(
+        file_path, evaluation_output_dir, current_score
+    )
+
     evaluation_config.conditionally_print_explanation()
     evaluation_config.conditionally_save_explanation_csv(file_path, evaluation_output_dir)
 
@@ -0,0 +1,44 @@
+"""
+
+ OMRChecker
+
+ Author: Udayraj Deshmukh
+ Github: https://github.com/Udayraj123
+
+"""
+
+
+class OMRCheckerError(Exception):
+    """Base class for the errors raised by OMRChecker, to tell them apart from other failures"""
+
+
+class InputDirNotFoundError(OMRCheckerError):
+    pass
+
+
+class TemplateNotFoundError(OMRCheckerError):
+    pass
+
+
+class TemplateInvalidError(OMRCheckerError):
+    pass
+
+
+class ConfigInvalidError(OMRCheckerError):
+    pass
+
+
+class EvaluationInvalidError(OMRCheckerError):
+    pass
+
+
+class JsonParseError(OMRCheckerError):
+    pass
+
+
+class ImageReadError(OMRCheckerError):
+    pass
+
+
+class ImageReadTimeoutError(ImageReadError):
+    pass
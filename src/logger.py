@@ -1,12 +1,24 @@
+import atexit
 import logging
+from contextlib import contextmanager
+from contextvars import ContextVar
+from time import perf_counter
 from typing import Union
 
 from rich.console import Console
 from rich.logging import RichHandler
+from rich.progress import (
+    BarColumn,
+    MofNCompleteColumn,
+    Progress,
+    TextColumn,
+    TimeRemainingColumn,
+)
 
 FORMAT = "%(message)s"
+# Name of the file being processed, prefixed to the messages logged meanwhile
+CURRENT_FILE = ContextVar("current_file", default=None)
 
-# TODO: set logging level from config.json dynamically
 logging.basicConfig(
     level=logging.INFO,
     format="%(message)s",
@@ -61,7 +73,97 @@ class Logger:
         func = getattr(self.log, method_type, None)
         if not func:
             raise AttributeError(f"Logger has no method {method_type}")
-        return func(sep.join(msg), stacklevel=4)
+        message = sep.join(msg)
+        current_file = CURRENT_FILE.get()
+        if current_file is not None and len(message) > 0:
+            message = f"[{current_file}] {message}"
+        return func(message, stacklevel=4)
+
+
+def set_log_level(level):
+    # Applies to all the loggers, e.g. "DEBUG" to troubleshoot or "WARNING" for quiet runs
+    logging.getLogger().setLevel(level.upper())
+
+
+def set_current_file(file_name):
+    CURRENT_FILE.set(file_name)
+
+
+def is_debug_enabled():
+    return logging.getLogger().isEnabledFor(logging.DEBUG)
+
+
+@contextmanager
+def time_stage(stage_timings, stage_name):
+    # Adds the seconds spent in the block to stage_timings, unless it is None
+    if stage_timings is None:
+        yield
+        return
+    start = perf_counter()
+    yield
+    stage_timings[stage_name] = round(
+        stage_timings.get(stage_name, 0) + perf_counter() - start, 4
+    )
+
+
+def redirect_logs_to_stderr():
+    # Keeps stdout free for machine-readable output
+    for handler in logging.getLogger().handlers:
+        if isinstance(handler, RichHandler):
+            handler.console = Console(stderr=True)
+    console.stderr = True
+
+
+class RunProgress:
+    """
+    Reports the files completed in a run, as a live bar with the throughput and
+        the time remaining on a terminal, or else as a log line every
+        report_every files so that piped logs stay readable.
+    """
+
+    def __init__(self, total_files, live=True, report_every=25):
+        self.total_files = total_files
+        self.completed_files = 0
+        self.report_every = report_every
+        self.start_time = perf_counter()
+        self.progress = None
+        if live and console.is_terminal:
+            self.progress = Progress(
+                TextColumn("Processing"),
+                BarColumn(),
+                MofNCompleteColumn(),
+                TextColumn("{task.fields[throughput]}"),
+                TimeRemainingColumn(),
+                console=console,
+                transient=True,
+            )
+            self.task = self.progress.add_task(
+                "files", total=total_files, throughput=""
+            )
+            self.progress.start()
+            # Restores the terminal when a run is interrupted
+            atexit.register(self.stop)
+
+    def advance(self):
+        self.completed_files += 1
+        throughput = self.completed_files / max(perf_counter() - self.start_time, 1e-6)
+        if self.progress is not None:
+            self.progress.update(
+                self.task, advance=1, throughput=f"{throughput:.2f} files/s"
+            )
+        elif (
+            self.completed_files % self.report_every == 0
+            or self.completed_files == self.total_files
+        ):
+            logger.info(
+                f"Progress: {self.completed_files}/{self.total_files} files ({throughput:.2f} files/s)"
+            )
+
+    def stop(self):
+        if self.progress is not None:
+            self.progress.stop()
+            atexit.unregister(self.stop)
+            self.progress = None
 
 
 logger = Logger(__name__)
@@ -1,7 +1,9 @@
 import cv2
 import numpy as np
 
+from src.logger import logger
 from src.processors.interfaces.ImagePreprocessor import ImagePreprocessor
+from src.utils.image import ImageUtils
 
 
 class Levels(ImagePreprocessor):
@@ -52,3 +54,109 @@ class GaussianBlur(ImagePreprocessor):
 
     def apply_filter(self, image, _file_path):
         return cv2.GaussianBlur(image, self.kSize, self.sigmaX)
+
+
+class Deskew(ImagePreprocessor):
+    def __init__(self, *args, **kwargs):
+        super().__init__(*args, **kwargs)
+        options = self.options
+        self.max_skew = options.get("maxSkew", 10)
+
+    def apply_filter(self, image, file_path):
+        image, skew_angle = ImageUtils.deskew(image, self.max_skew)
+        logger.info(f"Deskewed by {round(skew_angle, 2)} degrees: '{file_path}'")
+        return image
+
+
+class Morphology(ImagePreprocessor):
+    OPERATIONS = {
+        "erode": cv2.MORPH_ERODE,
+        "dilate": cv2.MORPH_DILATE,
+        "open": cv2.MORPH_OPEN,
+        "close": cv2.MORPH_CLOSE,
+    }
+    # Ellipse kernels clean up round bubbles without eating their edges
+    KERNEL_SHAPES = {
+        "rect": cv2.MORPH_RECT,
+        "ellipse": cv2.MORPH_ELLIPSE,
+        "cross": cv2.MORPH_CROSS,
+    }
+
+    def __init__(self, *args, **kwargs):
+        super().__init__(*args, **kwargs)
+        options = self.options
+        self.operation = self.OPERATIONS[options.get("operation", "open")]
+        self.kernel = cv2.getStructuringElement(
+            self.KERNEL_SHAPES[options.get("kernelShape", "rect")],
+            tuple(int(x) for x in options.get("kSize", (3, 3))),
+        )
+        self.iterations = int(options.get("iterations", 1))
+
+    def apply_filter(self, image, _file_path):
+        return cv2.morphologyEx(
+            image, self.operation, self.kernel, iterations=self.iterations
+        )
+
+
+class CLAHE(ImagePreprocessor):
+    def __init__(self, *args, **kwargs):
+        super().__init__(*args, **kwargs)
+        options = self.options
+        # Histogram bins are clipped at clipLimit times their average count
+        self.clahe = cv2.createCLAHE(
+            clipLimit=float(options.get("clipLimit", 2.0)),
+            tileGridSize=tuple(int(x) for x in options.get("tileGridSize", (8, 8))),
+        )
+
+    def apply_filter(self, image, _file_path):
+        return self.clahe.apply(image)
+
+
+class ContrastStretch(ImagePreprocessor):
+    def __init__(self, *args, **kwargs):
+        super().__init__(*args, **kwargs)
+        options = self.options
+        self.low_percentile = float(options.get("lowPercentile", 2))
+        self.high_percentile = float(options.get("highPercentile", 98))
+
+    def apply_filter(self, image, _file_path):
+        # Unlike a min-max stretch, a few stray dark or bright pixels don't limit it
+        low, high = np.percentile(image, (self.low_percentile, self.high_percentile))
+        if high <= low:
+            return image
+        stretched = (image.astype(np.float32) - low) * 255 / (high - low)
+        return np.clip(stretched, 0, 255).astype(np.uint8)
+
+
+class Threshold(ImagePreprocessor):
+    def __init__(self, *args, **kwargs):
+        super().__init__(*args, **kwargs)
+        options = self.options
+        # 'fixed' uses the threshold below, 'otsu' a global threshold per image
+        # and 'sauvola' a local one that follows uneven shading of the page
+        self.method = options.get("method", "fixed")
+        self.threshold = int(options.get("threshold", 127))
+        self.window = int(options.get("window", 25))
+        self.k = float(options.get("k", 0.2))
+        self.R = float(options.get("R", 128))
+
+    def get_sauvola_threshold(self, image):
+        # T = mean * (1 + k * (std / R - 1)) over a window around each pixel
+        image = image.astype(np.float32)
+        window_size = (self.window, self.window)
+        mean = cv2.boxFilter(image, -1, window_size)
+        mean_of_squares = cv2.boxFilter(image * image, -1, window_size)
+        std = np.sqrt(np.maximum(mean_of_squares - mean * mean, 0))
+        return mean * (1 + self.k * (std / self.R - 1))
+
+    def apply_filter(self, image, _file_path):
+        if self.method == "sauvola":
+            threshold = self.get_sauvola_threshold(image)
+            return np.where(image > threshold, 255, 0).astype(np.uint8)
+        if self.method == "otsu":
+            _, image = cv2.threshold(
+                image, 0, 255, cv2.THRESH_BINARY + cv2.THRESH_OTSU
+            )
+            return image
+        _, image = cv2.threshold(image, self.threshold, 255, cv2.THRESH_BINARY)
+        return image
@@ -2,8 +2,10 @@
 Processor/Extension framework
 Adapated from https://github.com/gdiepen/python_processor_example
 """
+import importlib.util
 import inspect
 import pkgutil
+import sys
 
 from src.logger import logger
 
@@ -75,6 +77,26 @@ class ProcessorManager:
 
         logger.info(f"Loaded processors: {loaded_packages}")
 
+    def load_processors_from_dir(self, processors_dir):
+        """Load the Processor sub classes of the python files in a directory outside
+        the package, so that users can add their own pre-processors without a fork
+        """
+        loaded_processors = []
+        for module_path in sorted(processors_dir.glob("*.py")):
+            module_name = f"omr_processors.{module_path.stem}"
+            spec = importlib.util.spec_from_file_location(module_name, module_path)
+            processor_module = importlib.util.module_from_spec(spec)
+            sys.modules[module_name] = processor_module
+            spec.loader.exec_module(processor_module)
+            for _, c in inspect.getmembers(
+                processor_module, ProcessorManager.get_name_filter(module_name)
+            ):
+                if issubclass(c, Processor) and c is not Processor:
+                    self.processors[c.__name__] = c
+                    loaded_processors.append(c.__name__)
+
+        logger.info(f"Loaded processors from '{processors_dir}': {loaded_processors}")
+
 
 # Singleton export
 PROCESSOR_MANAGER = ProcessorManager()
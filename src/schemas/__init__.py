@@ -1,4 +1,6 @@
 # https://docs.python.org/3/tutorial/modules.html#:~:text=The%20__init__.py,on%20the%20module%20search%20path.
+import json
+
 from jsonschema import Draft202012Validator
 
 from src.schemas.config_schema import CONFIG_SCHEMA
@@ -16,3 +18,8 @@ SCHEMA_VALIDATORS = {
     "evaluation": Draft202012Validator(EVALUATION_SCHEMA),
     "template": Draft202012Validator(TEMPLATE_SCHEMA),
 }
+
+
+def get_schema_json(schema_name):
+    # For editors to autocomplete and validate the json files as they are written
+    return json.dumps(SCHEMA_JSONS[schema_name], indent=2)
@@ -14,6 +14,25 @@ CONFIG_SCHEMA = {
                 "display_width": {"type": "integer"},
                 "processing_height": {"type": "integer"},
                 "processing_width": {"type": "integer"},
+                "resize_interpolation": {
+                    "enum": ["nearest", "linear", "area", "cubic", "lanczos"],
+                    "type": "string",
+                },
+            },
+        },
+        "inputs": {
+            "type": "object",
+            "additionalProperties": False,
+            "properties": {
+                "preserve_bit_depth": {"type": "boolean"},
+                "min_resolution_ratio": {"type": "number", "minimum": 0, "maximum": 1},
+                "max_aspect_deviation": {"type": "number", "minimum": 0},
+                "deduplicate": {
+                    "enum": ["off", "exact", "perceptual"],
+                    "type": "string",
+                },
+                "read_timeout_seconds": {"type": "number", "exclusiveMinimum": 0},
+                "read_retries": {"type": "integer", "minimum": 0},
             },
         },
         "threshold_params": {
@@ -26,9 +45,55 @@ CONFIG_SCHEMA = {
                 "CONFIDENT_SURPLUS": {"type": "integer", "minimum": 0, "maximum": 20},
                 "JUMP_DELTA": {"type": "integer", "minimum": 10, "maximum": 100},
                 "PAGE_TYPE_FOR_THRESHOLD": {
-                    "enum": ["white", "black"],
+                    "enum": ["white", "black", "dark"],
+                    "type": "string",
+                },
+                "DETECTION_STRATEGY": {
+                    "enum": ["relative_jump", "absolute", "ensemble"],
                     "type": "string",
                 },
+                "CONFIDENCE_AGGREGATION": {
+                    "enum": ["mean", "marked_only", "min_field", "median"],
+                    "type": "string",
+                },
+                "GLOBAL_PAGE_THRESHOLD_WHITE": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "maximum": 255,
+                },
+                "GLOBAL_PAGE_THRESHOLD_BLACK": {
+                    "type": "integer",
+                    "minimum": 0,
+                    "maximum": 255,
+                },
+                "BLANK_PAGE_INK_RATIO": {"type": "number", "minimum": 0, "maximum": 1},
+                "MANUAL_FIELD_INK_RATIO": {
+                    "type": "number",
+                    "minimum": 0,
+                    "maximum": 1,
+                },
+                "DARK_PIXEL_CUTOFF": {
+                    "oneOf": [
+                        {"type": "string", "enum": ["page", "auto"]},
+                        {"type": "integer", "minimum": 0, "maximum": 255},
+                    ]
+                },
+                "BUBBLE_INSET": {
+                    "type": "number",
+                    "minimum": 0,
+                    "exclusiveMaximum": 0.5,
+                },
+                "REVIEW_CONFIDENCE_THRESHOLD": {
+                    "type": "number",
+                    "minimum": 0,
+                    "maximum": 1,
+                },
+                "ERASURE_DARKNESS_RATIO": {"type": "number", "minimum": 0},
+                "REFERENCE_MARK_RATIO": {
+                    "type": "number",
+                    "exclusiveMinimum": 0,
+                    "exclusiveMaximum": 1,
+                },
             },
         },
         "alignment_params": {
@@ -40,6 +105,23 @@ CONFIG_SCHEMA = {
                 "max_steps": {"type": "integer", "minimum": 1, "maximum": 100},
                 "stride": {"type": "integer", "minimum": 1, "maximum": 10},
                 "thickness": {"type": "integer", "minimum": 1, "maximum": 10},
+                "retry_below_confidence": {
+                    "type": "number",
+                    "minimum": 0,
+                    "maximum": 1,
+                },
+                "snap_radius": {"type": "integer", "minimum": 0, "maximum": 20},
+            },
+        },
+        "auto_layout": {
+            "type": "object",
+            "additionalProperties": False,
+            "properties": {
+                "min_bubble_size": {"type": "integer", "minimum": 1},
+                "max_aspect_deviation": {"type": "number", "minimum": 0},
+                # Fraction of the bounding box enclosed by the bubble contour
+                "min_fill_ratio": {"type": "number", "minimum": 0, "maximum": 1},
+                "max_fill_ratio": {"type": "number", "minimum": 0, "maximum": 1},
             },
         },
         "outputs": {
@@ -51,6 +133,23 @@ CONFIG_SCHEMA = {
                 "save_detections": {"type": "boolean"},
                 # This option moves multimarked files into a separate folder for manual checking, skipping evaluation
                 "filter_out_multimarked_files": {"type": "boolean"},
+                # Also writes the results as an Excel workbook (requires openpyxl)
+                "save_xlsx": {"type": "boolean"},
+                "output_format": {
+                    "type": "string",
+                    "enum": ["files", "ndjson", "both"],
+                },
+                "preserve_input_structure": {"type": "boolean"},
+                "csv_delimiter": {"type": "string", "enum": [",", ";", "\t", "|"]},
+                "decimal_comma": {"type": "boolean"},
+                "reject_below_confidence": {
+                    "type": "number",
+                    "minimum": 0,
+                    "maximum": 1,
+                },
+                "save_mark_heatmap": {"type": "boolean"},
+                "embed_thumbnails": {"type": "boolean"},
+                "thumbnail_max_dim": {"type": "integer", "minimum": 16},
             },
         },
     },
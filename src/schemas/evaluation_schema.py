@@ -25,6 +25,32 @@ marking_object_properties = {
     },
 }
 
+# Scoring options common to all the source types
+scoring_options_properties = {
+    "should_explain_scoring": {"type": "boolean"},
+    # Saves a PDF sheet with the per question marks for each student
+    "enable_feedback_pdf": {"type": "boolean"},
+    # How to score a question with more than one marked bubble
+    "multi_marked_policy": {
+        "type": "string",
+        "enum": ["incorrect", "darkest", "any_correct", "as_blank"],
+    },
+    # Output column (usually a custom label) holding the roll number, enables the gradebook
+    "roll_number_field": {"type": "string"},
+    # Output column holding the bubbled exam version, used with --answerKeyDir
+    "version_field": {"type": "string"},
+    # Lower bound percentage and label pairs in descending order, e.g. [[80, "A"], [0, "F"]]
+    "grade_bands": {
+        "type": "array",
+        "items": {
+            "type": "array",
+            "prefixItems": [{"type": "number"}, {"type": "string"}],
+            "minItems": 2,
+            "maxItems": 2,
+        },
+    },
+}
+
 EVALUATION_SCHEMA = {
     "$schema": "https://json-schema.org/draft/2020-12/schema",
     "$id": "https://github.com/Udayraj123/OMRChecker/tree/master/src/schemas/evaluation-schema.json",
@@ -57,6 +83,10 @@ EVALUATION_SCHEMA = {
                             ]
                         },
                         "marking": marking_object_properties,
+                        # Multiplies the marks of every question in the section
+                        "weight": {"type": "number", "minimum": 0},
+                        # Lowest total the section can contribute, caps the negative marks
+                        "floor": {"type": "number"},
                     },
                 },
             },
@@ -78,7 +108,7 @@ EVALUATION_SCHEMA = {
                         },
                         "type": "object",
                         "properties": {
-                            "should_explain_scoring": {"type": "boolean"},
+                            **scoring_options_properties,
                             "answer_key_csv_path": {"type": "string"},
                             "answer_key_image_path": {"type": "string"},
                             "questions_in_order": ARRAY_OF_STRINGS,
@@ -96,7 +126,7 @@ EVALUATION_SCHEMA = {
                         "required": ["answers_in_order", "questions_in_order"],
                         "type": "object",
                         "properties": {
-                            "should_explain_scoring": {"type": "boolean"},
+                            **scoring_options_properties,
                             "answers_in_order": {
                                 "oneOf": [
                                     {
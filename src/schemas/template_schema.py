@@ -21,6 +21,16 @@ two_positive_numbers = {
     "maxItems": 2,
     "minItems": 2,
 }
+# Bubble sizes must be non-zero
+bubble_dimensions = {
+    "type": "array",
+    "prefixItems": [
+        {"type": "number", "exclusiveMinimum": 0},
+        {"type": "number", "exclusiveMinimum": 0},
+    ],
+    "maxItems": 2,
+    "minItems": 2,
+}
 zero_to_one_number = {
     "type": "number",
     "minimum": 0,
@@ -41,10 +51,36 @@ TEMPLATE_SCHEMA = {
     ],
     "additionalProperties": False,
     "properties": {
+        "extends": {
+            "description": "Path to a parent template (relative to this file) whose values are overridden by this template",
+            "type": "string",
+        },
+        "schemaVersion": {
+            "description": "The version of the template format, older templates are migrated on load. Missing means version 1",
+            "type": "integer",
+            "minimum": 1,
+        },
+        "barcodeRegion": {
+            "description": "The region [x, y, width, height] of a QR code holding the student id, it is read instead of the bubbled roll number in the gradebook",
+            "type": "array",
+            "items": positive_integer,
+            "minItems": 4,
+            "maxItems": 4,
+        },
         "bubbleDimensions": {
-            **two_positive_integers,
+            **bubble_dimensions,
             "description": "The dimensions of the overlay bubble area: [width, height]",
         },
+        "columns": {
+            "description": "The origins of the columns of a multi-column sheet. The fieldBlocks are repeated in each column, relative to its origin, and their field numbers continue from the previous column",
+            "type": "array",
+            "items": {
+                "type": "object",
+                "required": ["origin"],
+                "additionalProperties": False,
+                "properties": {"origin": two_positive_integers},
+            },
+        },
         "customLabels": {
             "description": "The customLabels contain fields that need to be joined together before generating the results sheet",
             "type": "object",
@@ -52,6 +88,22 @@ TEMPLATE_SCHEMA = {
                 "^.*$": {"type": "array", "items": FIELD_STRING_TYPE}
             },
         },
+        "dateLabels": {
+            "description": "The dateLabels join the digit fields of a date grid into an ISO date like 2001-12-25. Impossible dates, e.g. month 13, are flagged for review",
+            "type": "object",
+            "patternProperties": {
+                "^.*$": {
+                    "type": "object",
+                    "required": ["day", "month", "year"],
+                    "additionalProperties": False,
+                    "properties": {
+                        "day": {"type": "array", "items": FIELD_STRING_TYPE},
+                        "month": {"type": "array", "items": FIELD_STRING_TYPE},
+                        "year": {"type": "array", "items": FIELD_STRING_TYPE},
+                    },
+                }
+            },
+        },
         "outputColumns": {
             "type": "array",
             "items": FIELD_STRING_TYPE,
@@ -69,13 +121,26 @@ TEMPLATE_SCHEMA = {
                 "properties": {
                     "name": {
                         "type": "string",
-                        "enum": [
-                            "CropOnMarkers",
-                            "CropPage",
-                            "FeatureBasedAlignment",
-                            "GaussianBlur",
-                            "Levels",
-                            "MedianBlur",
+                        "anyOf": [
+                            {
+                                "enum": [
+                                    "CLAHE",
+                                    "ContrastStretch",
+                                    "CropOnMarkers",
+                                    "CropPage",
+                                    "Deskew",
+                                    "FeatureBasedAlignment",
+                                    "GaussianBlur",
+                                    "Levels",
+                                    "MedianBlur",
+                                    "Morphology",
+                                    "Threshold",
+                                ]
+                            },
+                            {
+                                "description": "The class name of a processor loaded with --processorsDir",
+                                "pattern": "^[A-Za-z_][A-Za-z0-9_]*$",
+                            },
                         ],
                     },
                 },
@@ -179,9 +244,156 @@ TEMPLATE_SCHEMA = {
                             }
                         },
                     },
+                    {
+                        "if": {"properties": {"name": {"const": "Deskew"}}},
+                        "then": {
+                            "properties": {
+                                "options": {
+                                    "type": "object",
+                                    "additionalProperties": False,
+                                    "properties": {
+                                        "maxSkew": {
+                                            "type": "number",
+                                            "minimum": 0,
+                                            "maximum": 45,
+                                        },
+                                    },
+                                }
+                            }
+                        },
+                    },
+                    {
+                        "if": {"properties": {"name": {"const": "Morphology"}}},
+                        "then": {
+                            "properties": {
+                                "options": {
+                                    "type": "object",
+                                    "additionalProperties": False,
+                                    "properties": {
+                                        "operation": {
+                                            "type": "string",
+                                            "enum": ["erode", "dilate", "open", "close"],
+                                        },
+                                        "kSize": two_positive_integers,
+                                        "kernelShape": {
+                                            "type": "string",
+                                            "enum": ["rect", "ellipse", "cross"],
+                                        },
+                                        "iterations": positive_integer,
+                                    },
+                                }
+                            }
+                        },
+                    },
+                    {
+                        "if": {"properties": {"name": {"const": "CLAHE"}}},
+                        "then": {
+                            "properties": {
+                                "options": {
+                                    "type": "object",
+                                    "additionalProperties": False,
+                                    "properties": {
+                                        "clipLimit": {
+                                            "type": "number",
+                                            "exclusiveMinimum": 0,
+                                        },
+                                        "tileGridSize": two_positive_integers,
+                                    },
+                                }
+                            }
+                        },
+                    },
+                    {
+                        "if": {"properties": {"name": {"const": "ContrastStretch"}}},
+                        "then": {
+                            "properties": {
+                                "options": {
+                                    "type": "object",
+                                    "additionalProperties": False,
+                                    "properties": {
+                                        "lowPercentile": {
+                                            "type": "number",
+                                            "minimum": 0,
+                                            "maximum": 100,
+                                        },
+                                        "highPercentile": {
+                                            "type": "number",
+                                            "minimum": 0,
+                                            "maximum": 100,
+                                        },
+                                    },
+                                }
+                            }
+                        },
+                    },
+                    {
+                        "if": {"properties": {"name": {"const": "Threshold"}}},
+                        "then": {
+                            "properties": {
+                                "options": {
+                                    "type": "object",
+                                    "additionalProperties": False,
+                                    "properties": {
+                                        "method": {
+                                            "type": "string",
+                                            "enum": ["fixed", "otsu", "sauvola"],
+                                        },
+                                        "threshold": {
+                                            "type": "integer",
+                                            "minimum": 0,
+                                            "maximum": 255,
+                                        },
+                                        "window": {
+                                            "description": "The odd side of the square window of the local 'sauvola' threshold",
+                                            "type": "integer",
+                                            "minimum": 3,
+                                        },
+                                        "k": {"type": "number", "minimum": 0},
+                                        "R": {
+                                            "description": "The dynamic range of the standard deviation for 'sauvola', 128 for 8-bit images",
+                                            "type": "number",
+                                            "exclusiveMinimum": 0,
+                                        },
+                                    },
+                                }
+                            }
+                        },
+                    },
                 ],
             },
         },
+        "regionOfInterest": {
+            "description": "The region [x, y, width, height] of the page holding the fields. Everything outside it, like printed instructions, is whitened out before detection",
+            "type": "array",
+            "items": positive_integer,
+            "minItems": 4,
+            "maxItems": 4,
+        },
+        "valueMap": {
+            "description": "Maps the printed bubble values to the values in the response, e.g. from '1' to 'A', for an answer key that uses other tokens",
+            "type": "object",
+            "additionalProperties": {"type": "string"},
+        },
+        "studentRegions": {
+            "description": "The origins of the student regions of a sheet shared by several students. The fieldBlocks are repeated in each region, relative to its origin, and each region is read as a separate result",
+            "type": "array",
+            "items": {
+                "type": "object",
+                "required": ["origin"],
+                "additionalProperties": False,
+                "properties": {"origin": two_positive_integers},
+            },
+        },
+        "referenceStrips": {
+            "description": "Regions [x, y, width, height] of solid black reference strips printed on the sheet, their darkness calibrates the mark threshold of each image",
+            "type": "array",
+            "items": {
+                "type": "array",
+                "items": positive_integer,
+                "minItems": 4,
+                "maxItems": 4,
+            },
+        },
         "fieldBlocks": {
             "description": "The fieldBlocks denote small groups of adjacent fields",
             "type": "object",
@@ -199,7 +411,10 @@ TEMPLATE_SCHEMA = {
                         {"required": ["bubbleValues", "direction"]},
                     ],
                     "properties": {
-                        "bubbleDimensions": two_positive_numbers,
+                        "bubbleDimensions": {
+                            **bubble_dimensions,
+                            "description": "Overrides the template bubbleDimensions for the bubbles of this field block",
+                        },
                         "bubblesGap": positive_number,
                         "bubbleValues": ARRAY_OF_STRINGS,
                         "direction": {
@@ -209,11 +424,27 @@ TEMPLATE_SCHEMA = {
                         "emptyValue": {"type": "string"},
                         "fieldLabels": {"type": "array", "items": FIELD_STRING_TYPE},
                         "labelsGap": positive_number,
+                        "markStyle": {
+                            "description": "'cross' and 'tick' also read thin X or tick strokes as marks, 'fill' expects filled bubbles",
+                            "type": "string",
+                            "enum": ["fill", "cross", "tick"],
+                        },
                         "origin": two_positive_integers,
+                        "rotation": {
+                            "description": "Angle in degrees by which the bubble rows are rotated clockwise about the origin, for slightly slanted prints",
+                            "type": "number",
+                            "minimum": -45,
+                            "maximum": 45,
+                        },
                         "fieldType": {
                             "type": "string",
                             "enum": list(FIELD_TYPES.keys()),
                         },
+                        "valueMap": {
+                            "description": "Maps the printed bubble values to the values in the response, e.g. from '1' to 'A'. Overrides the template valueMap for this field block",
+                            "type": "object",
+                            "additionalProperties": {"type": "string"},
+                        },
                     },
                 }
             },
@@ -0,0 +1,80 @@
+"""
+
+ OMRChecker
+
+ Author: Udayraj Deshmukh
+ Github: https://github.com/Udayraj123
+
+"""
+import json
+import tempfile
+from pathlib import Path
+
+from src.defaults import CONFIG_DEFAULTS
+from src.entry import process_image
+from src.logger import logger
+from src.template import Template
+from src.utils.layout import render_template_to_image
+
+SELF_TEST_TEMPLATE = {
+    "pageDimensions": [600, 800],
+    "bubbleDimensions": [30, 30],
+    "preProcessors": [],
+    "fieldBlocks": {
+        "MCQ_Block_1": {
+            "fieldType": "QTYPE_MCQ4",
+            "origin": [60, 80],
+            "fieldLabels": ["q1..10"],
+            "bubblesGap": 50,
+            "labelsGap": 50,
+        },
+        "Int_Block_1": {
+            "fieldType": "QTYPE_INT",
+            "origin": [350, 80],
+            "fieldLabels": ["q11..13"],
+            "bubblesGap": 50,
+            "labelsGap": 50,
+        },
+    },
+}
+
+
+def get_self_test_marks(template):
+    # A different bubble of each field, cycling through the bubble values
+    marks = {}
+    for field_block in template.field_blocks:
+        for field_bubbles in field_block.traverse_bubbles:
+            field_index = len(marks)
+            bubble = field_bubbles[field_index % len(field_bubbles)]
+            marks[bubble.field_label] = bubble.field_value
+    return marks
+
+
+def run_self_test(tuning_config=CONFIG_DEFAULTS):
+    """
+    Renders a synthetic sheet with known marks, detects it and compares the
+        detected responses with the drawn marks. Returns True when all of them match.
+    """
+    with tempfile.TemporaryDirectory() as temp_dir:
+        template_path = Path(temp_dir, "template.json")
+        with open(template_path, "w") as f:
+            json.dump(SELF_TEST_TEMPLATE, f)
+        template = Template(template_path, tuning_config)
+
+    marks = get_self_test_marks(template)
+    image = render_template_to_image(template, marks)
+    detection = process_image(image, template, "self_test.png")
+    omr_response = detection[0] if detection is not None else {}
+
+    mismatches = {
+        field_label: (value, omr_response.get(field_label))
+        for field_label, value in marks.items()
+        if omr_response.get(field_label) != str(value)
+    }
+    for field_label, (expected, detected) in mismatches.items():
+        logger.error(f"Self-test: '{field_label}' expected {expected}, got {detected}")
+    if len(mismatches) > 0:
+        logger.error(f"Self-test failed for {len(mismatches)}/{len(marks)} fields")
+        return False
+    logger.info(f"Self-test passed: all {len(marks)} fields were detected correctly")
+    return True
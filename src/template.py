@@ -6,49 +6,85 @@
  Github: https://github.com/Udayraj123
 
 """
-from src.constants import FIELD_TYPES
+import math
+from copy import copy
+
+from src.constants import FIELD_TYPES, MANUAL_FIELD_TYPE
 from src.core import ImageInstanceOps
+from src.exceptions import TemplateInvalidError
 from src.logger import logger
 from src.processors.manager import PROCESSOR_MANAGER
 from src.utils.parsing import (
     custom_sort_output_columns,
+    expand_columns,
+    offset_field_blocks,
     open_template_with_defaults,
     parse_fields,
 )
 
 
 class Template:
-    def __init__(self, template_path, tuning_config):
+    def __init__(self, template_path, tuning_config, validate_bounds=True):
         self.path = template_path
         self.image_instance_ops = ImageInstanceOps(tuning_config)
+        # Off for lint, which reports the bubbles outside the page instead
+        self.validate_bounds = validate_bounds
 
         json_object = open_template_with_defaults(template_path)
         (
+            columns_object,
             custom_labels_object,
+            date_labels_object,
             field_blocks_object,
             output_columns_array,
             pre_processors_object,
+            student_regions_array,
+            self.region_of_interest,
+            self.reference_strips,
+            self.barcode_region,
             self.bubble_dimensions,
             self.global_empty_val,
             self.options,
             self.page_dimensions,
+            self.value_map,
         ) = map(
             json_object.get,
             [
+                "columns",
                 "customLabels",
+                "dateLabels",
                 "fieldBlocks",
                 "outputColumns",
                 "preProcessors",
+                "studentRegions",
+                "regionOfInterest",
+                "referenceStrips",
+                "barcodeRegion",
                 "bubbleDimensions",
                 "emptyValue",
                 "options",
                 "pageDimensions",
+                "valueMap",
             ],
         )
 
         self.parse_output_columns(output_columns_array)
         self.setup_pre_processors(pre_processors_object, template_path.parent)
+        field_blocks_object = expand_columns(field_blocks_object, columns_object)
         self.setup_field_blocks(field_blocks_object)
+        self.parse_date_labels(date_labels_object)
+        # The digit fields of a date are joined like the custom labels
+        custom_labels_object = {
+            **custom_labels_object,
+            **{
+                date_label: [
+                    field_string
+                    for segment in ["day", "month", "year"]
+                    for field_string in date_label_object[segment]
+                ]
+                for date_label, date_label_object in date_labels_object.items()
+            },
+        }
         self.parse_custom_labels(custom_labels_object)
 
         non_custom_columns, all_custom_columns = (
@@ -61,6 +97,21 @@ class Template:
 
         self.validate_template_columns(non_custom_columns, all_custom_columns)
 
+        # Each student region of a multi-student sheet is detected as its own result
+        self.student_region_templates = [
+            self.get_region_template(field_blocks_object, student_region["origin"])
+            for student_region in student_regions_array
+        ]
+
+    def get_region_template(self, field_blocks_object, region_origin):
+        # A copy of this template with its field blocks moved into one student region
+        region_template = copy(self)
+        region_template.student_region_templates = []
+        region_template.setup_field_blocks(
+            offset_field_blocks(field_blocks_object, region_origin)
+        )
+        return region_template
+
     def parse_output_columns(self, output_columns_array):
         self.output_columns = parse_fields(f"Output Columns", output_columns_array)
 
@@ -68,6 +119,10 @@ class Template:
         # load image pre_processors
         self.pre_processors = []
         for pre_processor in pre_processors_object:
+            if pre_processor["name"] not in PROCESSOR_MANAGER.processors:
+                raise TemplateInvalidError(
+                    f"Unknown pre-processor '{pre_processor['name']}', available: {list(PROCESSOR_MANAGER.processors)}"
+                )
             ProcessorClass = PROCESSOR_MANAGER.processors[pre_processor["name"]]
             pre_processor_instance = ProcessorClass(
                 options=pre_processor["options"],
@@ -80,9 +135,21 @@ class Template:
         # Add field_blocks
         self.field_blocks = []
         self.all_parsed_labels = set()
+        self.manual_field_labels = set()
         for block_name, field_block_object in field_blocks_object.items():
             self.parse_and_add_field_block(block_name, field_block_object)
 
+    def parse_date_labels(self, date_labels_object):
+        self.date_labels = {
+            date_label: [
+                parse_fields(
+                    f"Date Label: {date_label}.{segment}", date_label_object[segment]
+                )
+                for segment in ["day", "month", "year"]
+            ]
+            for date_label, date_label_object in date_labels_object.items()
+        }
+
     def parse_custom_labels(self, custom_labels_object):
         all_parsed_custom_labels = set()
         self.custom_labels = {}
@@ -98,7 +165,7 @@ class Template:
                 logger.critical(
                     f"For '{custom_label}', Missing labels - {missing_custom_labels}"
                 )
-                raise Exception(
+                raise TemplateInvalidError(
                     f"Missing field block label(s) in the given template for {missing_custom_labels} from '{custom_label}'"
                 )
 
@@ -107,7 +174,7 @@ class Template:
                 logger.critical(
                     f"field strings overlap for labels: {label_strings} and existing custom labels: {all_parsed_custom_labels}"
                 )
-                raise Exception(
+                raise TemplateInvalidError(
                     f"The field strings for custom label '{custom_label}' overlap with other existing custom labels"
                 )
 
@@ -135,7 +202,7 @@ class Template:
         )
         if len(missing_output_columns) > 0:
             logger.critical(f"Missing output columns: {missing_output_columns}")
-            raise Exception(
+            raise TemplateInvalidError(
                 f"Some columns are missing in the field blocks for the given output columns"
             )
 
@@ -152,12 +219,16 @@ class Template:
         field_block_object = self.pre_fill_field_block(field_block_object)
         block_instance = FieldBlock(block_name, field_block_object)
         self.field_blocks.append(block_instance)
+        if block_instance.is_manual:
+            self.manual_field_labels.update(block_instance.parsed_field_labels)
         self.validate_parsed_labels(field_block_object["fieldLabels"], block_instance)
 
     def pre_fill_field_block(self, field_block_object):
         if "fieldType" in field_block_object:
             field_block_object = {
                 **field_block_object,
+                # The fieldType overrides any bubbleValues of the block, kept for lint
+                "declaredBubbleValues": field_block_object.get("bubbleValues"),
                 **FIELD_TYPES[field_block_object["fieldType"]],
             }
         else:
@@ -167,6 +238,9 @@ class Template:
             "direction": "vertical",
             "emptyValue": self.global_empty_val,
             "bubbleDimensions": self.bubble_dimensions,
+            "markStyle": "fill",
+            "rotation": 0,
+            "valueMap": self.value_map,
             **field_block_object,
         }
 
@@ -181,11 +255,15 @@ class Template:
             logger.critical(
                 f"An overlap found between field string: {field_labels} in block '{block_name}' and existing labels: {self.all_parsed_labels}"
             )
-            raise Exception(
+            raise TemplateInvalidError(
                 f"The field strings for field block {block_name} overlap with other existing fields"
             )
         self.all_parsed_labels.update(field_labels_set)
 
+        if not self.validate_bounds:
+            return
+        self.validate_bubble_bounds(block_instance)
+
         page_width, page_height = self.page_dimensions
         block_width, block_height = block_instance.dimensions
         [block_start_x, block_start_y] = block_instance.origin
@@ -201,10 +279,97 @@ class Template:
             or block_start_x < 0
             or block_start_y < 0
         ):
-            raise Exception(
+            raise TemplateInvalidError(
                 f"Overflowing field block '{block_name}' with origin {block_instance.origin} and dimensions {block_instance.dimensions} in template with dimensions {self.page_dimensions}"
             )
 
+    def validate_bubble_bounds(self, block_instance):
+        page_width, page_height = self.page_dimensions
+        bubble_width, bubble_height = block_instance.bubble_dimensions
+        for field_bubbles in block_instance.traverse_bubbles:
+            for bubble_index, bubble in enumerate(field_bubbles):
+                if (
+                    bubble.x < 0
+                    or bubble.y < 0
+                    or bubble.x + bubble_width > page_width
+                    or bubble.y + bubble_height > page_height
+                ):
+                    logger.critical(
+                        f"Bubble {bubble_index} of field '{bubble.field_label}' would be clipped by the page"
+                    )
+                    raise TemplateInvalidError(
+                        f"Overflowing bubble {bubble_index} of field '{bubble.field_label}' in field block '{block_instance.name}' with position {bubble} and dimensions {block_instance.bubble_dimensions} in template with dimensions {self.page_dimensions}"
+                    )
+
+    def lint(self):
+        """
+        Advisory checks of the bubble layout, beyond the validation that rejects
+            broken templates. Returns (category, message) pairs.
+        """
+        lint_warnings = []
+        for field_block in self.field_blocks:
+            box_w, box_h = field_block.bubble_dimensions
+            for field_bubbles in field_block.traverse_bubbles:
+                field_label = field_bubbles[0].field_label
+                positions = [(bubble.x, bubble.y) for bubble in field_bubbles]
+                if ImageInstanceOps.has_overlapping_boxes(positions, box_w, box_h):
+                    lint_warnings.append(
+                        (
+                            "overlapping_bubbles",
+                            f"Bubbles of field '{field_label}' in block '{field_block.name}' overlap each other",
+                        )
+                    )
+                field_values = [bubble.field_value for bubble in field_bubbles]
+                duplicate_values = sorted(
+                    {value for value in field_values if field_values.count(value) > 1}
+                )
+                if len(duplicate_values) > 0:
+                    lint_warnings.append(
+                        (
+                            "duplicate_values",
+                            f"Field '{field_label}' has more than one bubble with the values {duplicate_values}",
+                        )
+                    )
+                declared_values = field_block.declared_values
+                if len(field_bubbles) != len(declared_values):
+                    lint_warnings.append(
+                        (
+                            "bubble_count",
+                            f"Field '{field_label}' has {len(field_bubbles)} bubbles, but its block lists {len(declared_values)} bubbleValues {declared_values}",
+                        )
+                    )
+                labelled_count = len(field_bubbles) - len(field_block.unmapped_values)
+                if 0 < labelled_count < len(field_bubbles):
+                    # The unlabelled bubbles give their printed values in the response
+                    lint_warnings.append(
+                        (
+                            "bubble_count",
+                            f"Field '{field_label}' has {len(field_bubbles)} bubbles, but the valueMap labels only {labelled_count} of them, leaving {field_block.unmapped_values} as printed",
+                        )
+                    )
+                outside_values = [
+                    bubble.field_value
+                    for bubble in field_bubbles
+                    if not self.is_inside_page(bubble, box_w, box_h)
+                ]
+                if len(outside_values) > 0:
+                    lint_warnings.append(
+                        (
+                            "outside_page",
+                            f"Bubbles {outside_values} of field '{field_label}' are outside the page dimensions {self.page_dimensions}",
+                        )
+                    )
+        return lint_warnings
+
+    def is_inside_page(self, bubble, box_w, box_h):
+        page_width, page_height = self.page_dimensions
+        return (
+            bubble.x >= 0
+            and bubble.y >= 0
+            and bubble.x + box_w <= page_width
+            and bubble.y + box_h <= page_height
+        )
+
     def __str__(self):
         return str(self.path)
 
@@ -221,11 +386,15 @@ class FieldBlock:
             bubble_dimensions,
             bubble_values,
             bubbles_gap,
+            declared_values,
             direction,
             field_labels,
             field_type,
             labels_gap,
+            self.mark_style,
             origin,
+            rotation,
+            value_map,
             self.empty_val,
         ) = map(
             field_block_object.get,
@@ -233,19 +402,34 @@ class FieldBlock:
                 "bubbleDimensions",
                 "bubbleValues",
                 "bubblesGap",
+                "declaredBubbleValues",
                 "direction",
                 "fieldLabels",
                 "fieldType",
                 "labelsGap",
+                "markStyle",
                 "origin",
+                "rotation",
+                "valueMap",
                 "emptyValue",
             ],
         )
+        # Printed values listed in the template, for lint
+        self.declared_values = (
+            bubble_values if declared_values is None else declared_values
+        )
+        # Printed values without a label in a partly matching valueMap, for lint
+        self.unmapped_values = [
+            value for value in bubble_values if value not in value_map
+        ]
+        # Translates printed bubble values like '1' into the answer key tokens like 'A'
+        bubble_values = [value_map.get(value, value) for value in bubble_values]
         self.parsed_field_labels = parse_fields(
             f"Field Block Labels: {self.name}", field_labels
         )
         self.origin = origin
         self.bubble_dimensions = bubble_dimensions
+        self.is_manual = field_type == MANUAL_FIELD_TYPE
         self.calculate_block_dimensions(
             bubble_dimensions,
             bubble_values,
@@ -260,6 +444,8 @@ class FieldBlock:
             field_type,
             labels_gap,
         )
+        if rotation != 0:
+            self.rotate_bubble_grid(rotation)
 
     def calculate_block_dimensions(
         self,
@@ -306,6 +492,17 @@ class FieldBlock:
             self.traverse_bubbles.append(field_bubbles)
             lead_point[_v] += labels_gap
 
+    def rotate_bubble_grid(self, rotation):
+        # Rotates the bubble positions clockwise (in image coordinates) about the origin
+        angle = math.radians(rotation)
+        cos_angle, sin_angle = math.cos(angle), math.sin(angle)
+        origin_x, origin_y = self.origin
+        for field_bubbles in self.traverse_bubbles:
+            for bubble in field_bubbles:
+                dx, dy = bubble.x - origin_x, bubble.y - origin_y
+                bubble.x = round(origin_x + dx * cos_angle - dy * sin_angle)
+                bubble.y = round(origin_y + dx * sin_angle + dy * cos_angle)
+
 
 class Bubble:
     """
@@ -0,0 +1,20 @@
+import json
+
+from src.defaults import CONFIG_DEFAULTS
+from src.entry import process_image
+from src.template import Template
+from src.utils.layout import render_template_to_image
+
+
+def write_template(tmp_path, template_json, tuning_config=CONFIG_DEFAULTS):
+    template_path = tmp_path.joinpath("template.json")
+    with open(template_path, "w") as f:
+        json.dump(template_json, f)
+    return Template(template_path, tuning_config)
+
+
+def read_rendered_sheet(template, marks):
+    # Draws the marks onto a blank print of the template and reads them back
+    return process_image(
+        render_template_to_image(template, marks), template, "sheet.png"
+    )
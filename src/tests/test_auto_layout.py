@@ -0,0 +1,123 @@
+import json
+from copy import deepcopy
+
+import cv2
+import numpy as np
+from dotmap import DotMap
+
+from src.defaults import CONFIG_DEFAULTS
+from src.self_test import SELF_TEST_TEMPLATE, run_self_test
+from src.template import Template
+from src.utils.image import ImageUtils
+from src.utils.layout import (
+    generate_draft_template,
+    get_bubble_mark_counts,
+    is_likely_bubble,
+    render_mark_heatmap,
+)
+
+BUBBLE_RADIUS = 10
+
+
+def draw_synthetic_grid(block_origins, columns, rows, bubbles_gap=30, labels_gap=40):
+    image = np.full((600, 800), 255, dtype=np.uint8)
+    for origin_x, origin_y in block_origins:
+        for row in range(rows):
+            for column in range(columns):
+                center = (
+                    origin_x + column * bubbles_gap,
+                    origin_y + row * labels_gap,
+                )
+                cv2.circle(image, center, BUBBLE_RADIUS, 0, 2)
+    # A marked bubble should be detected like any other bubble
+    cv2.circle(image, block_origins[0], BUBBLE_RADIUS, 0, -1)
+    return image
+
+
+def test_four_column_grid():
+    image = draw_synthetic_grid([(100, 100), (400, 100)], columns=4, rows=5)
+
+    draft_template = generate_draft_template(image, CONFIG_DEFAULTS.auto_layout)
+
+    assert draft_template["pageDimensions"] == [800, 600]
+    field_blocks = list(draft_template["fieldBlocks"].values())
+    assert len(field_blocks) == 2
+    for field_block in field_blocks:
+        assert field_block["bubbleValues"] == ["A", "B", "C", "D"]
+        assert field_block["bubblesGap"] == 30
+        assert field_block["labelsGap"] == 40
+    assert field_blocks[0]["fieldLabels"] == ["q1..5"]
+    assert field_blocks[1]["fieldLabels"] == ["q6..10"]
+
+
+def get_shape_contour(draw_shape):
+    image = np.zeros((100, 100), dtype=np.uint8)
+    draw_shape(image)
+    contours = ImageUtils.grab_contours(
+        cv2.findContours(image, cv2.RETR_EXTERNAL, cv2.CHAIN_APPROX_SIMPLE)
+    )
+    return contours[0]
+
+
+def is_likely_bubble_shape(draw_shape):
+    contour = get_shape_contour(draw_shape)
+    return is_likely_bubble(contour, 50, CONFIG_DEFAULTS.auto_layout)
+
+
+def test_round_blob_is_a_bubble():
+    assert is_likely_bubble_shape(lambda image: cv2.circle(image, (50, 50), 12, 255, -1))
+
+
+def test_thin_line_is_not_a_bubble():
+    assert not is_likely_bubble_shape(
+        lambda image: cv2.rectangle(image, (20, 50), (80, 52), 255, -1)
+    )
+
+
+def test_filled_square_is_not_a_bubble():
+    assert not is_likely_bubble_shape(
+        lambda image: cv2.rectangle(image, (40, 40), (64, 64), 255, -1)
+    )
+
+
+def test_self_test_round_trip():
+    assert run_self_test()
+
+
+def test_self_test_with_nearest_resize():
+    tuning_config = DotMap(deepcopy(CONFIG_DEFAULTS.toDict()), _dynamic=False)
+    tuning_config.dimensions.resize_interpolation = "nearest"
+    assert run_self_test(tuning_config)
+
+
+def test_mark_heatmap(tmp_path):
+    template_path = tmp_path.joinpath("template.json")
+    with open(template_path, "w") as f:
+        json.dump(SELF_TEST_TEMPLATE, f)
+    template = Template(template_path, CONFIG_DEFAULTS)
+    # q1 is marked A on every sheet, q2 only on the first one
+    marked_bubbles_of_files = [{"q1": ["A"], "q2": ["B"]}, {"q1": ["A"], "q2": []}]
+
+    mark_counts = get_bubble_mark_counts(template, marked_bubbles_of_files)
+    heatmap = render_mark_heatmap(template, mark_counts, 2)
+
+    assert mark_counts["q1"] == {"A": 2, "B": 0, "C": 0, "D": 0}
+    assert mark_counts["q2"] == {"A": 0, "B": 1, "C": 0, "D": 0}
+    assert mark_counts["q13"]["0"] == 0
+
+    def get_bubble_color(field_label, field_value):
+        for field_block in template.field_blocks:
+            for field_bubbles in field_block.traverse_bubbles:
+                for bubble in field_bubbles:
+                    if (bubble.field_label, bubble.field_value) == (
+                        field_label,
+                        field_value,
+                    ):
+                        return heatmap[bubble.y + 5, bubble.x + 5].tolist()
+
+    def get_colormap_color(level):
+        return cv2.applyColorMap(np.uint8([[level]]), cv2.COLORMAP_JET)[0, 0].tolist()
+
+    assert get_bubble_color("q1", "A") == get_colormap_color(255)
+    assert get_bubble_color("q1", "B") == get_colormap_color(0)
+    assert get_bubble_color("q2", "B") == get_colormap_color(128)
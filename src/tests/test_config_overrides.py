@@ -0,0 +1,22 @@
+from src.defaults import CONFIG_DEFAULTS
+from src.entry import apply_config_overrides
+
+
+def test_no_overrides_keeps_config():
+    assert apply_config_overrides(CONFIG_DEFAULTS, {"autoAlign": False}) is (
+        CONFIG_DEFAULTS
+    )
+
+
+def test_auto_align_flag_enables_alignment():
+    tuning_config = apply_config_overrides(CONFIG_DEFAULTS, {"autoAlign": True})
+
+    assert tuning_config.alignment_params.auto_align is True
+    # The defaults are left untouched for other directories
+    assert CONFIG_DEFAULTS.alignment_params.auto_align is False
+
+
+def test_mark_threshold_flag():
+    tuning_config = apply_config_overrides(CONFIG_DEFAULTS, {"markThreshold": 150})
+
+    assert tuning_config.threshold_params.GLOBAL_PAGE_THRESHOLD_WHITE == 150
@@ -0,0 +1,434 @@
+import json
+from copy import deepcopy
+
+import cv2
+import numpy as np
+import pytest
+from dotmap import DotMap
+
+from src.core import ImageInstanceOps
+from src.defaults import CONFIG_DEFAULTS
+from src.entry import process_image
+from src.self_test import SELF_TEST_TEMPLATE, get_self_test_marks
+from src.template import Template
+from src.tests.sheet_utils import read_rendered_sheet, write_template
+from src.utils.layout import render_template_to_image
+
+# A uniformly dark scan: the unmarked bubbles are gray, the marked one is darker
+DARK_SCAN_STRIP = [60, 62, 20, 61]
+
+
+def get_instance_ops(detection_strategy):
+    config = DotMap(deepcopy(CONFIG_DEFAULTS.toDict()), _dynamic=False)
+    config.threshold_params.DETECTION_STRATEGY = detection_strategy
+    return ImageInstanceOps(config)
+
+
+def get_marked_indices(instance_ops, q_vals):
+    global_thr, _, _ = instance_ops.get_global_threshold(q_vals, looseness=4)
+    threshold = instance_ops.get_strip_threshold(
+        q_vals, global_thr, no_outliers=False, plot_show=False
+    )
+    return [i for i, q_val in enumerate(q_vals) if threshold > q_val]
+
+
+def test_relative_jump_on_dark_scan():
+    instance_ops = get_instance_ops("relative_jump")
+    assert get_marked_indices(instance_ops, DARK_SCAN_STRIP) == [2]
+
+
+def test_absolute_on_dark_scan():
+    instance_ops = get_instance_ops("absolute")
+    assert get_marked_indices(instance_ops, DARK_SCAN_STRIP) == [0, 1, 2, 3]
+
+
+def get_ensemble_detection(instance_ops, q_vals):
+    global_thr, _, _ = instance_ops.get_global_threshold(q_vals, looseness=4)
+    relative_threshold = instance_ops.get_strip_threshold(
+        q_vals, global_thr, no_outliers=False, plot_show=False
+    )
+    return instance_ops.get_ensemble_threshold(q_vals, relative_threshold)
+
+
+def test_ensemble_disagreement_needs_review():
+    instance_ops = get_instance_ops("ensemble")
+    # relative_jump marks only the darkest bubble, absolute marks all of them
+    _, _, strategies_agree = get_ensemble_detection(instance_ops, DARK_SCAN_STRIP)
+    assert not strategies_agree
+
+
+def test_ensemble_agreement_boosts_confidence():
+    instance_ops = get_instance_ops("ensemble")
+    q_vals = [230, 232, 60, 231]
+    threshold, confidence, strategies_agree = get_ensemble_detection(
+        instance_ops, q_vals
+    )
+
+    assert strategies_agree
+    assert [i for i, q_val in enumerate(q_vals) if threshold > q_val] == [2]
+    assert confidence >= instance_ops.get_field_confidence(q_vals, threshold)
+
+
+def get_bubble_separation(bubble_inset):
+    instance_ops = get_instance_ops("relative_jump")
+    instance_ops.tuning_config.threshold_params.BUBBLE_INSET = bubble_inset
+    image = np.full((40, 80), 255, dtype=np.uint8)
+    # A printed ring around an unmarked bubble, and a filled one
+    cv2.circle(image, (20, 20), 14, 0, 3)
+    cv2.circle(image, (60, 20), 14, 60, -1)
+    unmarked = instance_ops.get_bubble_mean(image, 5, 5, 30, 30)
+    marked = instance_ops.get_bubble_mean(image, 45, 5, 30, 30)
+    return unmarked - marked
+
+
+def test_bubble_inset_improves_separation():
+    assert get_bubble_separation(0.25) > get_bubble_separation(0) + 50
+
+
+def draw_jittered_sheet(template, marks, jitter):
+    page_width, page_height = template.page_dimensions
+    image = np.full((page_height, page_width), 255, dtype=np.uint8)
+    rng = np.random.default_rng(0)
+    for field_block in template.field_blocks:
+        box_w, box_h = field_block.bubble_dimensions
+        for field_bubbles in field_block.traverse_bubbles:
+            for bubble in field_bubbles:
+                dx, dy = rng.integers(-jitter, jitter + 1, 2)
+                is_marked = marks[bubble.field_label] == bubble.field_value
+                corners = (bubble.x + dx, bubble.y + dy)
+                opposite = (corners[0] + box_w - 1, corners[1] + box_h - 1)
+                cv2.rectangle(image, corners, opposite, 40, -1 if is_marked else 2)
+    return image
+
+
+@pytest.mark.parametrize("bubble_inset", [0, 0.25])
+def test_snapping_reads_jittered_bubbles(tmp_path, bubble_inset):
+    template_path = tmp_path.joinpath("template.json")
+    with open(template_path, "w") as f:
+        json.dump(SELF_TEST_TEMPLATE, f)
+    config = DotMap(deepcopy(CONFIG_DEFAULTS.toDict()), _dynamic=False)
+    config.alignment_params.snap_radius = 3
+    config.threshold_params.BUBBLE_INSET = bubble_inset
+    template = Template(template_path, config)
+    marks = get_self_test_marks(template)
+    image = draw_jittered_sheet(template, marks, jitter=3)
+
+    omr_response, *_ = process_image(image, template, "jittered.png")
+
+    assert all(omr_response[label] == str(value) for label, value in marks.items())
+
+
+def test_snapping_follows_a_shifted_bubble():
+    instance_ops = get_instance_ops("relative_jump")
+    image = np.full((40, 40), 255, dtype=np.uint8)
+    # The mark landed 3px off the template position at (10, 10)
+    image[13:33, 13:33] = 40
+    unsnapped = instance_ops.get_bubble_mean(image, 10, 10, 20, 20)
+
+    instance_ops.tuning_config.alignment_params.snap_radius = 3
+    assert instance_ops.get_bubble_mean(image, 10, 10, 20, 20) == 40
+    assert unsnapped > 80
+
+
+def test_snapping_keeps_empty_bubbles_light():
+    instance_ops = get_instance_ops("relative_jump")
+    instance_ops.tuning_config.threshold_params.BUBBLE_INSET = 0.2
+    instance_ops.tuning_config.alignment_params.snap_radius = 3
+    image = np.full((40, 40), 255, dtype=np.uint8)
+    # The printed ring of an empty bubble, 3px off the template position at (10, 10)
+    image[13:33, 13:33] = 40
+    image[15:31, 15:31] = 255
+
+    # The darkest position would pull a part of the ring into the box
+    assert instance_ops.get_bubble_mean(image, 10, 10, 20, 20) == 255
+
+
+def test_reference_strips_calibrate_exposures(tmp_path):
+    config = DotMap(deepcopy(CONFIG_DEFAULTS.toDict()), _dynamic=False)
+    config.threshold_params.DETECTION_STRATEGY = "absolute"
+    template = write_template(
+        tmp_path, {**SELF_TEST_TEMPLATE, "referenceStrips": [[10, 80, 30, 500]]}, config
+    )
+    marks = get_self_test_marks(template)
+    dark_scan = render_template_to_image(template, marks)
+    dark_scan[80:580, 10:40] = 0
+    # The same marks scanned at a much lighter exposure
+    faint_scan = (255 - (255 - dark_scan.astype(np.float32)) * 0.4).astype(np.uint8)
+
+    responses, thresholds = [], []
+    for scan in [dark_scan, faint_scan]:
+        omr_response, *_ = process_image(scan, template, "scan.png")
+        responses.append(omr_response)
+        thresholds.append(template.image_instance_ops.reference_threshold)
+
+    assert responses[0] == responses[1]
+    assert all(responses[0][label] == str(value) for label, value in marks.items())
+    assert None not in thresholds
+
+    instance_ops = template.image_instance_ops
+    # Without normalization, the cutoff still sits halfway between strip and paper
+    faint_threshold = instance_ops.get_reference_threshold(
+        faint_scan, template.reference_strips, [153, 255, 255, 255]
+    )
+    assert faint_threshold == 204
+
+
+def test_region_of_interest(tmp_path):
+    # Only the MCQ block lies within the region, the INT block is left out
+    template = write_template(
+        tmp_path, {**SELF_TEST_TEMPLATE, "regionOfInterest": [0, 0, 300, 800]}
+    )
+    marks = get_self_test_marks(template)
+
+    omr_response, *_ = read_rendered_sheet(template, marks)
+
+    for field_label, value in marks.items():
+        inside_region = field_label in [f"q{number}" for number in range(1, 11)]
+        assert omr_response[field_label] == (str(value) if inside_region else "")
+
+
+def test_small_region_of_interest_is_not_blank(tmp_path):
+    # Only the first two rows of the MCQ block, a small part of the page
+    template = write_template(
+        tmp_path, {**SELF_TEST_TEMPLATE, "regionOfInterest": [50, 70, 200, 100]}
+    )
+    marks = get_self_test_marks(template)
+
+    *_, omr_metrics = read_rendered_sheet(template, marks)
+
+    assert not omr_metrics["is_blank"]
+
+
+def test_manual_field_reads_marked_or_blank(tmp_path):
+    signature_block = {
+        "fieldType": "QTYPE_MANUAL",
+        "origin": [60, 650],
+        "fieldLabels": ["signature"],
+        "bubbleDimensions": [200, 60],
+        "bubblesGap": 0,
+        "labelsGap": 0,
+    }
+    template = write_template(
+        tmp_path,
+        {
+            **SELF_TEST_TEMPLATE,
+            "fieldBlocks": {
+                **SELF_TEST_TEMPLATE["fieldBlocks"],
+                "Signature_Block": signature_block,
+            },
+        },
+    )
+    marks = get_self_test_marks(template)
+    assert marks["signature"] == "MARKED"
+    # The empty box still has its printed border
+    blank_marks = {**marks, "signature": None}
+
+    signed_response, *_ = read_rendered_sheet(template, marks)
+    unsigned_response, *_ = read_rendered_sheet(template, blank_marks)
+
+    assert all(signed_response[label] == str(value) for label, value in marks.items())
+    assert unsigned_response["signature"] == ""
+    assert template.manual_field_labels == {"signature"}
+
+
+def test_template_with_only_manual_fields(tmp_path):
+    template = write_template(
+        tmp_path,
+        {
+            **SELF_TEST_TEMPLATE,
+            "fieldBlocks": {
+                "Signature_Block": {
+                    "fieldType": "QTYPE_MANUAL",
+                    "origin": [60, 650],
+                    "fieldLabels": ["signature"],
+                    "bubbleDimensions": [200, 60],
+                    "bubblesGap": 0,
+                    "labelsGap": 0,
+                }
+            },
+        },
+    )
+
+    omr_response, *_, omr_metrics = read_rendered_sheet(
+        template, {"signature": "MARKED"}
+    )
+
+    assert omr_response["signature"] == "MARKED"
+    assert omr_metrics["file_confidence"] == 1.0
+
+
+def draw_cross(image, x, y, box_w, box_h):
+    # Two thin diagonal strokes within the bubble outline
+    left, top, right, bottom = x + 4, y + 4, x + box_w - 5, y + box_h - 5
+    cv2.line(image, (left, top), (right, bottom), 40, 2)
+    cv2.line(image, (right, top), (left, bottom), 40, 2)
+
+
+def test_cross_marks_are_read_by_their_strokes(tmp_path):
+    instance_ops = get_instance_ops("relative_jump")
+    box = np.full((30, 30), 255, dtype=np.uint8)
+    draw_cross(box, 0, 0, 30, 30)
+    # The thin strokes leave a crossed bubble close to the paper intensity
+    assert instance_ops.get_stroke_mean(box, 0, 0, 30, 30) < 180
+    assert instance_ops.get_bubble_mean(box, 0, 0, 30, 30) > 200
+
+    mcq_block = SELF_TEST_TEMPLATE["fieldBlocks"]["MCQ_Block_1"]
+    template = write_template(
+        tmp_path,
+        {
+            **SELF_TEST_TEMPLATE,
+            "fieldBlocks": {
+                **SELF_TEST_TEMPLATE["fieldBlocks"],
+                "MCQ_Block_1": {**mcq_block, "markStyle": "cross"},
+            },
+        },
+    )
+    marks = get_self_test_marks(template)
+    crossed_labels = [f"q{number}" for number in range(1, 11)]
+    sheet = render_template_to_image(
+        template,
+        {label: value for label, value in marks.items() if label not in crossed_labels},
+    )
+    box_w, box_h = template.bubble_dimensions
+    for field_bubbles in template.field_blocks[0].traverse_bubbles:
+        for bubble in field_bubbles:
+            if marks[bubble.field_label] == bubble.field_value:
+                draw_cross(sheet, bubble.x, bubble.y, box_w, box_h)
+
+    omr_response, *_ = process_image(sheet, template, "crosses.png")
+
+    assert all(omr_response[label] == str(value) for label, value in marks.items())
+
+
+def test_overlapping_bubbles_split_their_shared_pixels():
+    instance_ops = get_instance_ops("absolute")
+    # Two 30px wide bubbles only 20px apart, a mark fills the first one
+    image = np.full((30, 60), 255, dtype=np.uint8)
+    image[:, :30] = 40
+    positions = [(0, 0), (20, 0)]
+    threshold = instance_ops.get_global_default_threshold()
+
+    plain_means = [
+        instance_ops.get_bubble_mean(image, x, y, 30, 30) for x, y in positions
+    ]
+    closest_pixel_means = instance_ops.get_closest_pixel_means(image, positions, 30, 30)
+
+    assert instance_ops.has_overlapping_boxes(positions, 30, 30)
+    assert [q_val < threshold for q_val in plain_means] == [True, True]
+    assert [q_val < threshold for q_val in closest_pixel_means] == [True, False]
+
+
+def test_blank_page_detection():
+    instance_ops = get_instance_ops("relative_jump")
+    blank_page = np.full((400, 300), 255, dtype=np.uint8)
+    marked_page = blank_page.copy()
+    # A printed box and a filled bubble
+    marked_page[50:60, 20:280] = 0
+    marked_page[100:120, 100:120] = 30
+
+    assert instance_ops.is_blank_page(instance_ops.get_ink_ratio(blank_page))
+    assert not instance_ops.is_blank_page(instance_ops.get_ink_ratio(marked_page))
+
+
+def test_dark_pixel_cutoff_on_gamma_shifted_page():
+    instance_ops = get_instance_ops("relative_jump")
+    page = np.full((400, 300), 255, dtype=np.uint8)
+    # Marks that a brightening gamma has lifted above the page threshold
+    page[100:160, 100:160] = 215
+
+    assert instance_ops.is_blank_page(instance_ops.get_ink_ratio(page))
+    instance_ops.tuning_config.threshold_params.DARK_PIXEL_CUTOFF = "auto"
+    assert not instance_ops.is_blank_page(instance_ops.get_ink_ratio(page))
+    instance_ops.tuning_config.threshold_params.DARK_PIXEL_CUTOFF = 230
+    assert instance_ops.get_ink_ratio(page) == 0.03
+
+
+def test_faint_erasure_is_not_multi_marked():
+    instance_ops = get_instance_ops("relative_jump")
+    # The final answer 'B' is dark, the erased 'C' left a faint ghost
+    q_val_by_bubble = {"A": 250, "B": 60, "C": 180, "D": 250}
+    detected_bubbles = ["B", "C"]
+
+    assert instance_ops.drop_erased_bubbles(
+        detected_bubbles, q_val_by_bubble, 200
+    ) == ["B", "C"]
+    instance_ops.tuning_config.threshold_params.ERASURE_DARKNESS_RATIO = 3
+    assert instance_ops.drop_erased_bubbles(
+        detected_bubbles, q_val_by_bubble, 200
+    ) == ["B"]
+    # Two equally dark marks stay multi-marked
+    assert instance_ops.drop_erased_bubbles(
+        ["A", "B"], {**q_val_by_bubble, "A": 70}, 200
+    ) == ["A", "B"]
+
+
+def test_field_confidence():
+    instance_ops = get_instance_ops("relative_jump")
+    # A clear mark far away from the threshold
+    assert instance_ops.get_field_confidence([200, 200, 60, 200], 130) == 1.0
+    # A bubble lying on the threshold
+    assert instance_ops.get_field_confidence([200, 130, 60, 200], 130) == 0.0
+
+
+@pytest.mark.parametrize(
+    "aggregation,file_confidence",
+    [("mean", 0.84), ("marked_only", 0.6), ("min_field", 0.4), ("median", 1.0)],
+)
+def test_confidence_aggregation(aggregation, file_confidence):
+    instance_ops = get_instance_ops("relative_jump")
+    instance_ops.tuning_config.threshold_params.CONFIDENCE_AGGREGATION = aggregation
+    # Two unsure marks among confidently blank fields
+    field_confidences = {"q1": 0.8, "q2": 0.4, "q3": 1.0, "q4": 1.0, "q5": 1.0}
+    marked_bubbles = {"q1": ["A"], "q2": ["C"], "q3": [], "q4": [], "q5": []}
+
+    assert (
+        instance_ops.get_file_confidence(field_confidences, marked_bubbles)
+        == file_confidence
+    )
+
+
+def get_needs_review(instance_ops, q_vals, threshold):
+    field_confidence = instance_ops.get_field_confidence(q_vals, threshold)
+    return instance_ops.get_needs_review(q_vals, threshold, field_confidence)
+
+
+def test_needs_review():
+    instance_ops = get_instance_ops("relative_jump")
+    # A clear mark
+    assert not get_needs_review(instance_ops, [200, 200, 60, 200], 130)
+    # An unmarked field
+    assert not get_needs_review(instance_ops, [200, 198, 201, 199], 130)
+    # A borderline mark close to the threshold
+    assert get_needs_review(instance_ops, [200, 200, 125, 200], 130)
+    # Two equally dark marks
+    assert get_needs_review(instance_ops, [200, 62, 60, 200], 130)
+
+
+def get_detection(answer, file_confidence):
+    omr_metrics = {"file_confidence": file_confidence}
+    return {"q1": answer}, None, 0, False, omr_metrics
+
+
+def test_low_confidence_retries_with_alignment(mocker):
+    instance_ops = get_instance_ops("relative_jump")
+    instance_ops.tuning_config.alignment_params.retry_below_confidence = 0.5
+    page = np.full((400, 300), 255, dtype=np.uint8)
+    mocker.patch.object(instance_ops, "apply_preprocessors", return_value=page)
+    read_omr_response = mocker.patch.object(
+        instance_ops,
+        "read_omr_response",
+        side_effect=[get_detection("A", 0.2), get_detection("B", 0.9)],
+    )
+    template = DotMap(
+        image_instance_ops=instance_ops,
+        page_dimensions=[300, 400],
+        custom_labels={},
+        non_custom_labels=["q1"],
+    )
+
+    omr_response, _, _, omr_metrics = process_image(page, template, "sheet.jpg")
+
+    assert read_omr_response.call_count == 2
+    assert read_omr_response.call_args.kwargs["auto_align"] is True
+    assert omr_response == {"q1": "B"}
+    assert omr_metrics["alignment_path"] == "aligned_retry"
@@ -0,0 +1,296 @@
+import json
+from argparse import Namespace
+
+import pandas as pd
+
+from src.defaults import CONFIG_DEFAULTS
+from src.evaluation import (
+    EvaluationConfig,
+    VersionedEvaluationConfig,
+    build_gradebook,
+    evaluate_concatenated_response,
+    get_agreement_report,
+    get_item_analysis,
+)
+
+TEMPLATE = Namespace(global_empty_val="", manual_field_labels=set())
+MARKING = {"correct": 4, "incorrect": -1, "unmarked": 0}
+
+
+def get_evaluation_config(
+    tmp_path, options=None, marking_schemes=None, template=TEMPLATE
+):
+    evaluation_path = tmp_path.joinpath("evaluation.json")
+    with open(evaluation_path, "w") as f:
+        json.dump(
+            {
+                "source_type": "custom",
+                "options": {
+                    "questions_in_order": ["q1..3"],
+                    "answers_in_order": ["A", "B", "C"],
+                    **(options or {}),
+                },
+                "marking_schemes": {"DEFAULT": MARKING, **(marking_schemes or {})},
+            },
+            f,
+        )
+    return EvaluationConfig(tmp_path, evaluation_path, template, CONFIG_DEFAULTS)
+
+
+def evaluate(evaluation_config, tmp_path, response, omr_metrics=None):
+    return evaluate_concatenated_response(
+        response,
+        evaluation_config,
+        tmp_path.joinpath("sheet.png"),
+        tmp_path,
+        omr_metrics,
+    )
+
+
+# q1 is correct, q3 is unmarked and q2 has the right 'B' marked darker than the wrong 'C'
+MULTI_MARKED_RESPONSE = {"q1": "A", "q2": "BC", "q3": ""}
+MULTI_MARKED_METRICS = {"multi_marked_values": {"q2": ["B", "C"]}}
+
+
+def evaluate_with_policy(tmp_path, multi_marked_policy):
+    evaluation_config = get_evaluation_config(
+        tmp_path, {"multi_marked_policy": multi_marked_policy}
+    )
+    return evaluate(
+        evaluation_config, tmp_path, MULTI_MARKED_RESPONSE, MULTI_MARKED_METRICS
+    )
+
+
+def test_multi_marked_policy_incorrect(tmp_path):
+    assert evaluate_with_policy(tmp_path, "incorrect") == 3
+
+
+def test_multi_marked_policy_darkest(tmp_path):
+    assert evaluate_with_policy(tmp_path, "darkest") == 8
+
+
+def test_multi_marked_policy_any_correct(tmp_path):
+    assert evaluate_with_policy(tmp_path, "any_correct") == 8
+
+
+def test_multi_marked_policy_as_blank(tmp_path):
+    assert evaluate_with_policy(tmp_path, "as_blank") == 4
+
+
+def test_section_floor_clamps_negative_marks(tmp_path):
+    evaluation_config = get_evaluation_config(
+        tmp_path,
+        marking_schemes={
+            "SECTION_B": {
+                "questions": ["q2..3"],
+                "marking": MARKING,
+                "weight": 2,
+                "floor": -3,
+            }
+        },
+    )
+
+    # Both weighted wrong answers would total -4 in SECTION_B
+    score = evaluate(evaluation_config, tmp_path, {"q1": "A", "q2": "A", "q3": "A"})
+
+    assert evaluation_config.get_section_scores() == {
+        "DEFAULT": 4,
+        "SECTION_B": -3,
+    }
+    assert score == 1
+
+
+def test_omitted_questions(tmp_path):
+    evaluation_config = get_evaluation_config(
+        tmp_path,
+        {
+            "questions_in_order": ["q1..5"],
+            "answers_in_order": ["A", "B", "C", "D", "A"],
+            "multi_marked_policy": "as_blank",
+        },
+    )
+    # q1 is correct, q2 and q5 are blank, q3 is wrong and q4 is multi-marked
+    response = {"q1": "A", "q2": "", "q3": "A", "q4": "CD", "q5": ""}
+
+    score = evaluate(
+        evaluation_config,
+        tmp_path,
+        response,
+        {"multi_marked_values": {"q4": ["C", "D"]}},
+    )
+
+    assert score == 3
+    assert evaluation_config.get_omitted_questions() == ["q2", "q5"]
+
+    # The list is reset for each sheet
+    evaluate(evaluation_config, tmp_path, {**response, "q2": "B", "q5": "A"})
+    assert evaluation_config.get_omitted_questions() == []
+
+
+def test_manual_field_is_not_scored(tmp_path):
+    evaluation_config = get_evaluation_config(
+        tmp_path,
+        {
+            "questions_in_order": ["q1..3", "signature"],
+            "answers_in_order": ["A", "B", "C", "MARKED"],
+        },
+        template=Namespace(global_empty_val="", manual_field_labels={"signature"}),
+    )
+
+    assert evaluation_config.questions_in_order == ["q1", "q2", "q3"]
+    assert evaluation_config.get_max_score() == 12
+    response = {"q1": "A", "q2": "B", "q3": "C", "signature": ""}
+    assert evaluate(evaluation_config, tmp_path, response) == 12
+
+
+STANDARD_GRADE_BANDS = [[80, "A"], [70, "B"], [60, "C"], [50, "D"], [0, "F"]]
+
+
+def test_grade_bands(tmp_path):
+    evaluation_config = get_evaluation_config(
+        tmp_path,
+        {
+            "questions_in_order": ["q1..5"],
+            "answers_in_order": ["A", "B", "C", "D", "A"],
+            "grade_bands": STANDARD_GRADE_BANDS,
+        },
+    )
+    response = {"q1": "A", "q2": "B", "q3": "C", "q4": "D", "q5": ""}
+
+    # Exactly 80% lands in the 80-A band
+    score = evaluate(evaluation_config, tmp_path, response)
+    assert evaluation_config.get_max_score() == 20
+    assert evaluation_config.get_grade(score) == "A"
+    assert evaluation_config.get_grade(15.9) == "B"
+    assert evaluation_config.get_grade(12) == "C"
+    assert evaluation_config.get_grade(0) == "F"
+
+
+def test_feedback_pdf(tmp_path):
+    evaluation_config = get_evaluation_config(
+        tmp_path,
+        {"enable_feedback_pdf": True, "grade_bands": STANDARD_GRADE_BANDS},
+    )
+
+    evaluate(evaluation_config, tmp_path, {"q1": "A", "q2": "C", "q3": ""})
+
+    with open(tmp_path.joinpath("sheet_feedback.pdf"), "rb") as f:
+        pdf_bytes = f.read()
+    assert pdf_bytes.startswith(b"%PDF")
+    assert pdf_bytes.rstrip().endswith(b"%%EOF")
+
+
+def test_item_analysis():
+    evaluation_records = [
+        ({"q1": "correct", "q2": "correct", "q3": "correct"}, 10),
+        ({"q1": "correct", "q2": "incorrect", "q3": "correct"}, 8),
+        ({"q1": "unmarked", "q2": "correct-B", "q3": "correct"}, 4),
+        ({"q1": "incorrect", "q2": "unmarked", "q3": "correct"}, 2),
+    ]
+
+    item_analysis = get_item_analysis(evaluation_records)
+
+    # Mean score is 6 and its standard deviation is sqrt(10)
+    assert item_analysis["q1"] == {"difficulty": 0.5, "discrimination": 0.9487}
+    assert item_analysis["q2"] == {"difficulty": 0.5, "discrimination": 0.3162}
+    # Everyone answered q3 correctly, so it doesn't discriminate
+    assert item_analysis["q3"] == {"difficulty": 1.0, "discrimination": None}
+
+
+def test_agreement_report():
+    machine = pd.DataFrame(
+        {
+            "file_id": ["s1.png", "s2.png", "s3.png", "s4.png"],
+            "q1": ["A", "B", "A", "B"],
+            "q2": ["A", "B", "A", "B"],
+        }
+    )
+    # The human grader disagrees on both the questions of s4
+    human = machine.assign(q1=["A", "B", "A", "A"], q2=["A", "B", "A", "A"])
+
+    report = get_agreement_report(machine, human, ["q1", "q2"])
+
+    assert report["match_rates"] == {"q1": 0.75, "q2": 0.75}
+    # Observed agreement 6/8, chance agreement (4/8)(6/8) + (4/8)(2/8) = 1/2
+    assert report["kappa"] == 0.5
+    assert report["disagreements"] == [
+        ("s4.png", "q1", "B", "A"),
+        ("s4.png", "q2", "B", "A"),
+    ]
+
+
+def test_gradebook_by_roll_number(tmp_path):
+    evaluation_config = get_evaluation_config(
+        tmp_path,
+        {
+            "questions_in_order": ["q1..10"],
+            "answers_in_order": ["A", "B", "C", "D", "A", "B", "C", "D", "A", "B"],
+            "roll_number_field": "Roll",
+        },
+    )
+    student_responses = [
+        {"Roll": "1001", **{f"q{i}": "A" for i in range(1, 11)}},
+        {"Roll": "1002", **{f"q{i}": "" for i in range(1, 11)}, "q2": "B"},
+        {"Roll": "1001", **{f"q{i}": "" for i in range(1, 11)}},
+    ]
+    gradebook_records = []
+    for response in student_responses:
+        score = evaluate(evaluation_config, tmp_path, response)
+        gradebook_records.append(
+            (response["Roll"], evaluation_config.get_question_marks(), score)
+        )
+
+    gradebook = build_gradebook(
+        gradebook_records, evaluation_config.questions_in_order
+    )
+
+    assert gradebook.index.to_list() == ["1001", "1002", "1001_2"]
+    assert gradebook.columns.to_list() == [f"q{i}" for i in range(1, 11)] + ["total"]
+    # Three correct and seven incorrect answers
+    assert gradebook.loc["1001", "q1"] == 4
+    assert gradebook.loc["1001", "q2"] == -1
+    assert gradebook.loc["1001", "total"] == 5
+    assert gradebook.loc["1002", "total"] == 4
+    assert gradebook.loc["1001_2", "total"] == 0
+
+
+def test_no_grade_bands(tmp_path):
+    evaluation_config = get_evaluation_config(tmp_path)
+
+    assert evaluation_config.get_grade(12) is None
+
+
+def test_answer_key_versions(tmp_path):
+    # Version B holds the questions of version A in reverse order
+    for version, answers in [("A", ["A", "B", "C"]), ("B", ["C", "B", "A"])]:
+        with open(tmp_path.joinpath(f"{version}.json"), "w") as f:
+            json.dump(
+                {
+                    "source_type": "custom",
+                    "options": {
+                        "questions_in_order": ["q1..3"],
+                        "answers_in_order": answers,
+                    },
+                    "marking_schemes": {"DEFAULT": MARKING},
+                },
+                f,
+            )
+    template = Namespace(
+        global_empty_val="",
+        manual_field_labels=set(),
+        output_columns=["version", "q1", "q2", "q3"],
+    )
+    versioned_config = VersionedEvaluationConfig(tmp_path, template, CONFIG_DEFAULTS)
+    sheets = [
+        {"version": "A", "q1": "A", "q2": "B", "q3": "C"},
+        {"version": "B", "q1": "C", "q2": "B", "q3": "A"},
+    ]
+
+    scores = [
+        evaluate(versioned_config.get_config_for_response(sheet), tmp_path, sheet)
+        for sheet in sheets
+    ]
+
+    assert scores == [12, 12]
+    assert versioned_config.get_config_for_response({"version": ""}) is None
+    assert versioned_config.get_config_for_response({"version": "C"}) is None
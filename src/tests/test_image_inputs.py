@@ -0,0 +1,211 @@
+import base64
+import shutil
+import time
+from pathlib import Path
+
+import cv2
+import numpy as np
+import pytest
+from PIL import Image, ImageOps
+
+from src.exceptions import ImageReadTimeoutError
+from src.utils.file import find_duplicate_files
+from src.utils.image import ImageUtils
+
+
+def write_narrow_16_bit_gradient(tmp_path):
+    # A faint gradient spanning a narrow band of the 16-bit range
+    gradient = np.tile(np.arange(30000, 30512, 2, dtype=np.uint16), (64, 1))
+    image_path = str(tmp_path.joinpath("gradient.tif"))
+    cv2.imwrite(image_path, gradient)
+    return image_path
+
+
+def test_16_bit_gradient_is_not_posterized(tmp_path):
+    image_path = write_narrow_16_bit_gradient(tmp_path)
+
+    truncated = ImageUtils.read_image_grayscale(image_path)
+    preserved = ImageUtils.read_image_grayscale(image_path, preserve_bit_depth=True)
+
+    assert preserved.dtype == np.uint8
+    assert len(np.unique(truncated)) <= 3
+    assert len(np.unique(preserved)) == 256
+
+
+def test_cmyk_jpeg_intensities(tmp_path):
+    image_path = str(tmp_path.joinpath("cmyk.jpg"))
+    # 50% black ink
+    Image.new("CMYK", (40, 20), (0, 0, 0, 128)).save(image_path, quality=100)
+
+    loaded = ImageUtils.read_image_grayscale(image_path)
+
+    assert loaded.shape == (20, 40)
+    assert abs(int(loaded.mean()) - 127) <= 3
+
+
+def test_palette_png_intensities(tmp_path):
+    image_path = str(tmp_path.joinpath("palette.png"))
+    image = Image.new("P", (40, 20), 0)
+    image.putpalette([255, 0, 0, 0, 0, 255])
+    image.paste(1, (20, 0, 40, 20))
+    image.save(image_path)
+
+    loaded = ImageUtils.read_image_grayscale(image_path)
+
+    # Luminance of pure red and pure blue
+    assert abs(int(loaded[:, :20].mean()) - 76) <= 1
+    assert abs(int(loaded[:, 20:].mean()) - 29) <= 1
+
+
+def test_cached_gamma_table():
+    image = np.arange(256, dtype=np.uint8).reshape(16, 16)
+    expected = (((image / 255.0) ** (1 / 0.7)) * 255).astype("uint8")
+
+    assert np.array_equal(ImageUtils.adjust_gamma(image, 0.7), expected)
+    assert ImageUtils.get_gamma_table(0.7) is ImageUtils.get_gamma_table(0.7)
+
+
+def test_qr_code_student_id():
+    qr_code = cv2.QRCodeEncoder.create().encode("STU-2024-017")
+    # Scale up the modules and add a quiet zone, like a printed code
+    qr_code = cv2.resize(qr_code, None, fx=8, fy=8, interpolation=cv2.INTER_NEAREST)
+    qr_code = cv2.copyMakeBorder(
+        qr_code, 40, 40, 40, 40, cv2.BORDER_CONSTANT, value=255
+    )
+
+    assert ImageUtils.decode_qr_code(qr_code) == "STU-2024-017"
+    assert ImageUtils.decode_qr_code(np.full((200, 200), 255, np.uint8)) is None
+
+
+def write_scans_with_duplicates(tmp_path):
+    sheet = np.full((200, 150), 255, dtype=np.uint8)
+    sheet[40:60, 30:120] = 0
+    other_sheet = np.full((200, 150), 255, dtype=np.uint8)
+    other_sheet[:, :75] = 0
+    scan_paths = [tmp_path.joinpath(name) for name in ["a.png", "b.png", "c.png"]]
+    cv2.imwrite(str(scan_paths[0]), sheet)
+    shutil.copy(scan_paths[0], scan_paths[1])
+    cv2.imwrite(str(scan_paths[2]), other_sheet)
+    return scan_paths
+
+
+def test_exact_duplicates_are_skipped(tmp_path):
+    scan_paths = write_scans_with_duplicates(tmp_path)
+
+    unique_files, duplicates = find_duplicate_files(scan_paths, "exact")
+
+    assert unique_files == [scan_paths[0], scan_paths[2]]
+    assert duplicates == {scan_paths[1]: scan_paths[0]}
+
+
+def test_perceptual_duplicates_are_skipped(tmp_path):
+    scan_paths = write_scans_with_duplicates(tmp_path)
+    # A lossy re-encoding of the first scan
+    jpeg_path = tmp_path.joinpath("a.jpg")
+    cv2.imwrite(
+        str(jpeg_path), cv2.imread(str(scan_paths[0])), [cv2.IMWRITE_JPEG_QUALITY, 90]
+    )
+
+    unique_files, duplicates = find_duplicate_files(
+        scan_paths + [jpeg_path], "perceptual"
+    )
+
+    assert unique_files == [scan_paths[0], scan_paths[2]]
+    assert duplicates == {scan_paths[1]: scan_paths[0], jpeg_path: scan_paths[0]}
+
+
+def test_deskew_grid():
+    grid = np.full((400, 400), 255, dtype=np.uint8)
+    for position in range(40, 400, 60):
+        grid[position : position + 3, 20:380] = 0
+        grid[20:380, position : position + 3] = 0
+    rotation_matrix = cv2.getRotationMatrix2D((200, 200), 2.5, 1.0)
+    skewed = cv2.warpAffine(grid, rotation_matrix, (400, 400), borderValue=255)
+
+    _, skew_angle = ImageUtils.deskew(skewed)
+
+    assert abs(skew_angle - 2.5) < 0.5
+
+
+def write_oriented_jpeg(tmp_path, orientation):
+    # Distinct quadrants so that every rotation/flip gives a different image
+    pixels = np.zeros((40, 80), dtype=np.uint8)
+    pixels[:20, 40:] = 85
+    pixels[20:, :40] = 170
+    pixels[20:, 40:] = 255
+    image = Image.fromarray(pixels)
+    exif = image.getexif()
+    exif[0x0112] = orientation
+    image_path = tmp_path.joinpath(f"oriented_{orientation}.jpg")
+    image.save(image_path, exif=exif, quality=100)
+    return str(image_path)
+
+
+@pytest.mark.parametrize("orientation", range(1, 9))
+def test_exif_orientation_is_applied(tmp_path, orientation):
+    image_path = write_oriented_jpeg(tmp_path, orientation)
+
+    upright = np.array(ImageOps.exif_transpose(Image.open(image_path)))
+    loaded = ImageUtils.read_image_grayscale(image_path)
+
+    assert loaded.shape == upright.shape
+    assert np.abs(loaded.astype(int) - upright.astype(int)).mean() < 5
+
+
+def test_blurred_scan_has_a_lower_blur_score():
+    sample_path = Path(__file__).parent.joinpath(
+        "test_samples", "sample1", "sample.png"
+    )
+    image = cv2.imread(str(sample_path), cv2.IMREAD_GRAYSCALE)
+    blurred = cv2.GaussianBlur(image, (9, 9), 0)
+
+    assert ImageUtils.get_blur_score(blurred) < ImageUtils.get_blur_score(image) / 2
+    assert ImageUtils.get_contrast_score(np.full((10, 10), 128, np.uint8)) == 0
+
+
+def test_image_read_is_retried_after_an_io_error(mocker):
+    image = np.full((20, 40), 255, dtype=np.uint8)
+    read_image_file = mocker.patch.object(
+        ImageUtils,
+        "read_image_file",
+        side_effect=[OSError("Stale file handle"), image],
+    )
+    sleep = mocker.patch("src.utils.image.sleep")
+
+    loaded = ImageUtils.read_image_with_retry("scan.png", retries=2)
+
+    assert loaded is image
+    assert read_image_file.call_count == 2
+    sleep.assert_called_once()
+
+
+def test_undecodable_image_is_not_retried(tmp_path, mocker):
+    image_path = tmp_path.joinpath("truncated.png")
+    image_path.write_bytes(b"\x89PNG\r\n\x1a\n")
+    read_image_file = mocker.spy(ImageUtils, "read_image_file")
+
+    assert ImageUtils.read_image_with_retry(str(image_path), retries=2) is None
+    assert read_image_file.call_count == 1
+
+
+def test_stalled_image_read_times_out(mocker):
+    mocker.patch.object(
+        ImageUtils, "read_image_file", side_effect=lambda *_: time.sleep(2)
+    )
+
+    with pytest.raises(ImageReadTimeoutError):
+        ImageUtils.read_image_with_retry("stalled.png", timeout_seconds=0.1)
+
+
+def test_thumbnail_decodes_to_a_downscaled_image():
+    # A 800x1200 gradient
+    image = np.tile(np.linspace(0, 255, 800, dtype=np.uint8), (1200, 1))
+
+    thumbnail = ImageUtils.get_thumbnail_base64(image, 256)
+
+    decoded = cv2.imdecode(
+        np.frombuffer(base64.b64decode(thumbnail), dtype=np.uint8),
+        cv2.IMREAD_GRAYSCALE,
+    )
+    assert decoded is not None
+    assert decoded.shape == (256, 171)
@@ -0,0 +1,60 @@
+import logging
+
+from src.logger import (
+    RunProgress,
+    is_debug_enabled,
+    logger,
+    set_current_file,
+    set_log_level,
+    time_stage,
+)
+
+
+def test_log_level_and_current_file(caplog):
+    try:
+        set_log_level("warning")
+        set_current_file("sheet.png")
+        logger.info("Reading bubbles")
+        logger.warning("Low confidence")
+    finally:
+        set_log_level("info")
+        set_current_file(None)
+    logger.warning("Finished")
+
+    assert [record.getMessage() for record in caplog.records] == [
+        "[sheet.png] Low confidence",
+        "Finished",
+    ]
+    assert logging.getLogger().level == logging.INFO
+
+
+def test_stage_timings_only_when_debugging():
+    stage_timings = {} if is_debug_enabled() else None
+    with time_stage(stage_timings, "load"):
+        pass
+    assert stage_timings is None
+
+    try:
+        set_log_level("debug")
+        stage_timings = {} if is_debug_enabled() else None
+        for _ in range(2):
+            with time_stage(stage_timings, "detect"):
+                pass
+    finally:
+        set_log_level("info")
+    assert list(stage_timings.keys()) == ["detect"]
+    assert stage_timings["detect"] >= 0
+
+
+def test_run_progress_without_a_terminal(caplog):
+    run_progress = RunProgress(5, report_every=2)
+    for _ in range(5):
+        run_progress.advance()
+    run_progress.stop()
+
+    assert run_progress.completed_files == 5
+    assert [
+        record.getMessage().split(" (")[0]
+        for record in caplog.records
+        if record.getMessage().startswith("Progress")
+    ] == ["Progress: 2/5 files", "Progress: 4/5 files", "Progress: 5/5 files"]
@@ -0,0 +1,473 @@
+import json
+import shutil
+import time
+from argparse import Namespace
+from copy import deepcopy
+from pathlib import Path
+
+import numpy as np
+import pandas as pd
+import pytest
+from dotmap import DotMap
+
+from src.defaults import CONFIG_DEFAULTS
+from src.entry import (
+    entry_point,
+    get_omr_files,
+    get_output_dir,
+    score_results_file,
+    watch_input_dirs,
+)
+from src.logger import RunProgress
+from src.tests.test_samples.sample1.boilerplate import TEMPLATE_BOILERPLATE
+from src.utils.file import (
+    Paths,
+    append_results_row,
+    clear_in_progress,
+    get_confidence_histogram,
+    mark_in_progress,
+    setup_dirs_for_paths,
+    setup_outputs_for_template,
+    write_aggregate_results,
+    write_atomically,
+)
+from src.utils.image import ImageUtils
+
+
+def setup_outputs(tmp_path, output_columns):
+    paths = Paths(tmp_path)
+    setup_dirs_for_paths(paths)
+    template = Namespace(output_columns=output_columns)
+    return setup_outputs_for_template(paths, template)
+
+
+def test_results_csv_header_order(tmp_path):
+    outputs_namespace = setup_outputs(tmp_path, ["q1", "q2"])
+
+    header = pd.read_csv(outputs_namespace.filesMap["Results"]).columns.to_list()
+    assert header == [
+        "file_id",
+        "input_path",
+        "output_path",
+        "score",
+        "q1",
+        "q2",
+        "file_confidence",
+        "multi_marked",
+        "is_blank",
+        "needs_review",
+        "blur_score",
+        "contrast_score",
+        "manually_corrected",
+    ]
+
+
+def test_semicolon_delimited_decimal_comma_results(tmp_path):
+    tuning_config = DotMap(deepcopy(CONFIG_DEFAULTS.toDict()), _dynamic=False)
+    tuning_config.outputs.csv_delimiter = ";"
+    tuning_config.outputs.decimal_comma = True
+    paths = Paths(tmp_path)
+    setup_dirs_for_paths(paths)
+    outputs_namespace = setup_outputs_for_template(
+        paths, Namespace(output_columns=["q1", "q2"]), tuning_config
+    )
+
+    append_results_row(
+        outputs_namespace,
+        "Results",
+        ["a.png", "in/a.png", "out/a.png", 7.5, "A", "BC", 0.82, False, False, ""],
+    )
+
+    with open(outputs_namespace.filesMap["Results"]) as f:
+        lines = f.read().splitlines()
+    assert lines[0].split(";")[:5] == [
+        '"file_id"',
+        '"input_path"',
+        '"output_path"',
+        '"score"',
+        '"q1"',
+    ]
+    assert lines[1].split(";")[3:7] == ['"7,5"', '"A"', '"BC"', '"0,82"']
+
+
+def test_preserve_input_structure(tmp_path):
+    tuning_config = DotMap(deepcopy(CONFIG_DEFAULTS.toDict()), _dynamic=False)
+    tuning_config.outputs.preserve_input_structure = True
+    root_dir = Path("inputs", "School")
+    args = {"output_dir": tmp_path}
+
+    output_dirs = [
+        get_output_dir(root_dir, root_dir.joinpath(class_dir), args, tuning_config)
+        for class_dir in ["Class-A", "Class-B"]
+    ]
+
+    assert output_dirs == [
+        tmp_path.joinpath("School", "Class-A"),
+        tmp_path.joinpath("School", "Class-B"),
+    ]
+    for output_dir, file_id in zip(output_dirs, ["a.png", "b.png"]):
+        outputs_namespace = setup_outputs(output_dir, ["q1"])
+        pd.DataFrame([[file_id, "", "", 0, "A", 1.0, False, False, ""]]).to_csv(
+            outputs_namespace.filesMap["Results"], mode="a", header=False, index=False
+        )
+    # A copy next to the results is not aggregated a second time
+    results_path = Path(outputs_namespace.filesMap["Results"])
+    results_path.with_name(f"{results_path.stem}_backup.csv").write_text(
+        results_path.read_text()
+    )
+
+    write_aggregate_results(tmp_path.joinpath("School"))
+
+    aggregate_results = pd.read_csv(
+        tmp_path.joinpath("School", "AggregateResults.csv"), dtype=str
+    )
+    assert aggregate_results["batch"].to_list() == ["Class-A", "Class-B"]
+    assert aggregate_results["file_id"].to_list() == ["a.png", "b.png"]
+
+
+def test_watch_processes_new_files(tmp_path, mocker):
+    input_dir = tmp_path.joinpath("inputs")
+    input_dir.mkdir()
+    with open(input_dir.joinpath("template.json"), "w") as f:
+        json.dump(TEMPLATE_BOILERPLATE, f)
+    sample_path = Path(__file__).parent.joinpath(
+        "test_samples", "sample1", "sample.png"
+    )
+    new_file_path = input_dir.joinpath("sheet.png")
+    process_files = mocker.patch("src.entry.process_files")
+
+    def add_scan_once(_seconds):
+        # A scan lands in the folder after the first poll
+        if not new_file_path.exists():
+            shutil.copy(sample_path, new_file_path)
+
+    mocker.patch("src.entry.sleep", side_effect=add_scan_once)
+    args = {
+        "output_dir": tmp_path.joinpath("outputs"),
+        "setLayout": False,
+        "watch": 0,
+    }
+
+    watch_input_dirs([input_dir], args, max_polls=3)
+
+    assert process_files.call_count == 1
+    assert process_files.call_args.args[0] == [new_file_path]
+
+
+def test_watch_aggregates_span_all_polls(tmp_path, mocker):
+    input_dir = tmp_path.joinpath("inputs")
+    input_dir.mkdir()
+    with open(input_dir.joinpath("template.json"), "w") as f:
+        json.dump(TEMPLATE_BOILERPLATE, f)
+    sample_path = Path(__file__).parent.joinpath(
+        "test_samples", "sample1", "sample.png"
+    )
+    shutil.copy(sample_path, input_dir.joinpath("a.png"))
+    new_file_path = input_dir.joinpath("b.png")
+    process_files = mocker.patch("src.entry.process_files")
+
+    def add_scan_once(_seconds):
+        if not new_file_path.exists():
+            shutil.copy(sample_path, new_file_path)
+
+    mocker.patch("src.entry.sleep", side_effect=add_scan_once)
+    args = {
+        "output_dir": tmp_path.joinpath("outputs"),
+        "setLayout": False,
+        "watch": 0,
+    }
+
+    watch_input_dirs([input_dir], args, max_polls=2)
+
+    # Both polls add to the same outputs, so the aggregates cover every file
+    first_poll, second_poll = process_files.call_args_list
+    assert first_poll.args[0] != second_poll.args[0]
+    assert first_poll.args[4] is second_poll.args[4]
+
+
+def get_detection(file_confidence):
+    omr_response = {f"q{number}": "A" for number in range(1, 6)}
+    omr_metrics = {
+        "field_confidences": {label: file_confidence for label in omr_response},
+        "file_confidence": file_confidence,
+        "is_blank": False,
+        "ink_ratio": 0.1,
+        "blur_score": 120.0,
+        "contrast_score": 60.0,
+        "needs_review": [],
+        "multi_marked_values": {},
+        "marked_bubbles": {label: ["A"] for label in omr_response},
+        "alignment_path": "fast",
+        "student_id": None,
+    }
+    return omr_response, np.zeros((400, 300), dtype=np.uint8), 0, omr_metrics
+
+
+def setup_input_dir(tmp_path, file_names, config):
+    input_dir = tmp_path.joinpath("inputs")
+    input_dir.mkdir()
+    with open(input_dir.joinpath("template.json"), "w") as f:
+        json.dump(TEMPLATE_BOILERPLATE, f)
+    with open(input_dir.joinpath("config.json"), "w") as f:
+        json.dump(config, f)
+    sample_path = Path(__file__).parent.joinpath(
+        "test_samples", "sample1", "sample.png"
+    )
+    for file_name in file_names:
+        shutil.copy(sample_path, input_dir.joinpath(file_name))
+    return input_dir
+
+
+def test_low_confidence_sheet_is_rejected(tmp_path, mocker):
+    input_dir = setup_input_dir(
+        tmp_path,
+        ["good.png", "poor.png"],
+        {"outputs": {"reject_below_confidence": 0.6}},
+    )
+    mocker.patch(
+        "src.entry.process_image",
+        side_effect=lambda image, template, file_path, **_: get_detection(
+            0.3 if file_path.name == "poor.png" else 0.9
+        ),
+    )
+    output_dir = tmp_path.joinpath("outputs")
+
+    entry_point(input_dir, {"output_dir": output_dir, "setLayout": False})
+
+    paths = Paths(output_dir)
+    results = pd.read_csv(next(paths.results_dir.glob("Results_*.csv")), dtype=str)
+    rejected = pd.read_csv(paths.manual_dir.joinpath("RejectedFiles.csv"), dtype=str)
+    assert results["file_id"].to_list() == ["good.png"]
+    assert rejected["file_id"].to_list() == ["poor.png"]
+    assert rejected["score"].to_list() == ["NA"]
+    assert paths.rejected_dir.joinpath("poor.png").exists()
+
+
+def test_corrections_override_detected_values(tmp_path, mocker):
+    input_dir = setup_input_dir(tmp_path, ["a.png", "b.png"], {})
+    with open(input_dir.joinpath("corrections.json"), "w") as f:
+        json.dump({"a.png": {"q2": "D"}}, f)
+    mocker.patch(
+        "src.entry.process_image",
+        side_effect=lambda *_, **__: get_detection(0.9),
+    )
+    output_dir = tmp_path.joinpath("outputs")
+
+    entry_point(input_dir, {"output_dir": output_dir, "setLayout": False})
+
+    results_path = next(Paths(output_dir).results_dir.glob("Results_*.csv"))
+    results = pd.read_csv(results_path, dtype=str, keep_default_na=False)
+    assert results["q2"].to_list() == ["D", "A"]
+    assert results["q1"].to_list() == ["A", "A"]
+    assert results["manually_corrected"].to_list() == ["q2", ""]
+
+
+def test_resume_after_interruption(tmp_path, mocker):
+    file_names = ["a.png", "b.png", "c.png", "d.png"]
+    input_dir = setup_input_dir(tmp_path, file_names, {})
+    with open(input_dir.joinpath("evaluation.json"), "w") as f:
+        json.dump(
+            {
+                "source_type": "custom",
+                "options": {
+                    "questions_in_order": ["q1..3"],
+                    "answers_in_order": ["A", "B", "C"],
+                },
+                "marking_schemes": {
+                    "DEFAULT": {"correct": 4, "incorrect": -1, "unmarked": 0}
+                },
+            },
+            f,
+        )
+    output_dir = tmp_path.joinpath("outputs")
+    args = {"output_dir": output_dir, "setLayout": False}
+
+    def read_student_sheet(image, template, file_path, **_):
+        omr_response, final_marked, multi_marked, omr_metrics = get_detection(0.9)
+        omr_metrics["student_id"] = file_path.stem
+        return omr_response, final_marked, multi_marked, omr_metrics
+
+    def crash_on_c(image, template, file_path, **_):
+        if file_path.name == "c.png":
+            raise RuntimeError("Interrupted")
+        return read_student_sheet(image, template, file_path)
+
+    process_image = mocker.patch("src.entry.process_image", side_effect=crash_on_c)
+    with pytest.raises(RuntimeError):
+        entry_point(input_dir, args)
+    process_image.side_effect = read_student_sheet
+    process_image.reset_mock()
+
+    entry_point(input_dir, {**args, "resume": True})
+
+    resumed_files = [call.args[2].name for call in process_image.call_args_list]
+    assert resumed_files == ["c.png", "d.png"]
+    results = pd.concat(
+        pd.read_csv(results_path, dtype=str)
+        for results_path in Paths(output_dir).results_dir.glob("Results_*.csv")
+    )
+    assert sorted(results["file_id"].to_list()) == file_names
+    # The aggregates also cover the files read before the interruption
+    gradebook = pd.read_csv(
+        Paths(output_dir).evaluation_dir.joinpath("gradebook.csv"), dtype=str
+    )
+    assert gradebook["roll_number"].to_list() == ["a", "b", "c", "d"]
+
+
+def test_run_progress_counts_every_file(tmp_path, mocker):
+    input_dir = setup_input_dir(tmp_path, ["a.png", "b.png", "c.png"], {})
+    # An unreadable sheet ends up in the errors but is still counted
+    mocker.patch(
+        "src.entry.process_image",
+        side_effect=lambda image, template, file_path, **_: (
+            None if file_path.name == "b.png" else get_detection(0.9)
+        ),
+    )
+    advance = mocker.spy(RunProgress, "advance")
+
+    entry_point(
+        input_dir, {"output_dir": tmp_path.joinpath("outputs"), "setLayout": False}
+    )
+
+    assert advance.call_count == 3
+    assert advance.call_args.args[0].completed_files == 3
+
+
+def test_timed_out_read_does_not_stop_the_batch(tmp_path, mocker):
+    input_dir = setup_input_dir(
+        tmp_path, ["a.png", "b.png", "c.png"], {"inputs": {"read_timeout_seconds": 0.1}}
+    )
+    read_image_file = ImageUtils.read_image_file
+
+    def stall_on_b(path, *args):
+        if Path(path).name == "b.png":
+            time.sleep(2)
+        return read_image_file(path, *args)
+
+    mocker.patch.object(ImageUtils, "read_image_file", side_effect=stall_on_b)
+    mocker.patch("src.entry.process_image", return_value=get_detection(0.9))
+    output_dir = tmp_path.joinpath("outputs")
+
+    entry_point(input_dir, {"output_dir": output_dir, "setLayout": False})
+
+    paths = Paths(output_dir)
+    results = pd.read_csv(next(paths.results_dir.glob("Results_*.csv")), dtype=str)
+    assert results["file_id"].to_list() == ["a.png", "c.png"]
+    errors = pd.read_csv(paths.manual_dir.joinpath("ErrorFiles.csv"), dtype=str)
+    assert errors["file_id"].to_list() == ["b.png"]
+
+
+def test_outputs_manifest(tmp_path, mocker):
+    input_dir = setup_input_dir(tmp_path, ["sheet.png"], {})
+    mocker.patch("src.entry.process_image", return_value=get_detection(0.9))
+    output_dir = tmp_path.joinpath("outputs")
+
+    entry_point(input_dir, {"output_dir": output_dir, "setLayout": False})
+
+    paths = Paths(output_dir)
+    with open(paths.outputs_manifest_path) as f:
+        manifest = json.load(f)
+    written_files = {
+        file_path.relative_to(output_dir).as_posix(): file_path.stat().st_size
+        for file_path in output_dir.glob("**/*")
+        if file_path.is_file()
+        and file_path not in [paths.outputs_manifest_path, paths.progress_path]
+    }
+    assert {
+        entry["path"]: entry["bytes"] for entry in manifest["files"]
+    } == written_files
+    assert "Results/confidence_histogram.json" in written_files
+    assert manifest["template_path"] == str(input_dir.joinpath("template.json"))
+    assert len(manifest["config_hash"]) == 64
+
+
+def test_input_files_order(tmp_path):
+    for file_name in ["b.png", "C.jpg", "a.JPEG", "B2.tif", "notes.txt"]:
+        tmp_path.joinpath(file_name).touch()
+
+    omr_files = get_omr_files(tmp_path)
+
+    assert [f.name for f in omr_files] == ["a.JPEG", "b.png", "B2.tif", "C.jpg"]
+    assert get_omr_files(tmp_path) == omr_files
+
+
+def test_score_only_round_trip(tmp_path):
+    input_dir = tmp_path.joinpath("inputs")
+    input_dir.mkdir()
+    with open(input_dir.joinpath("template.json"), "w") as f:
+        json.dump(TEMPLATE_BOILERPLATE, f)
+    with open(input_dir.joinpath("evaluation.json"), "w") as f:
+        json.dump(
+            {
+                "source_type": "custom",
+                "options": {
+                    "questions_in_order": ["q1..3"],
+                    "answers_in_order": ["A", "B", "C"],
+                },
+                "marking_schemes": {
+                    "DEFAULT": {"correct": 4, "incorrect": -1, "unmarked": 0}
+                },
+            },
+            f,
+        )
+    # Results of an earlier run, written without an answer key
+    outputs_namespace = setup_outputs(tmp_path, ["q1", "q2", "q3", "q4", "q5"])
+    results_path = outputs_namespace.filesMap["Results"]
+    pd.DataFrame(
+        [
+            ["a.png", "inputs/a.png", "a.png", "0", "A", "B", "C", "D", "E"]
+            + outputs_namespace.empty_metrics,
+            ["b.png", "inputs/b.png", "b.png", "0", "A", "C", "", "D", "E"]
+            + outputs_namespace.empty_metrics,
+        ]
+    ).to_csv(results_path, mode="a", header=False, index=False)
+
+    rescored_path = score_results_file(
+        results_path, input_dir, {"output_dir": tmp_path.joinpath("outputs")}
+    )
+
+    rescored = pd.read_csv(rescored_path, dtype=str, keep_default_na=False)
+    assert rescored["file_id"].to_list() == ["a.png", "b.png"]
+    assert rescored["score"].astype(float).to_list() == [12.0, 3.0]
+    assert rescored["q2"].to_list() == ["B", "C"]
+
+
+def test_interrupted_write_keeps_previous_file(tmp_path):
+    gradebook_path = tmp_path.joinpath("gradebook.csv")
+    gradebook_path.write_text("roll_number,total\n1,10\n")
+
+    def write_partially(temp_path):
+        with open(temp_path, "w") as f:
+            f.write("roll_number,total\n")
+        raise KeyboardInterrupt
+
+    with pytest.raises(KeyboardInterrupt):
+        write_atomically(gradebook_path, write_partially)
+    assert gradebook_path.read_text() == "roll_number,total\n1,10\n"
+    assert list(tmp_path.iterdir()) == [gradebook_path]
+
+    gradebook = pd.DataFrame({"roll_number": ["1", "2"], "total": [10, 8]})
+    write_atomically(
+        gradebook_path, lambda temp_path: gradebook.to_csv(temp_path, index=False)
+    )
+    assert pd.read_csv(gradebook_path, dtype=str)["roll_number"].to_list() == ["1", "2"]
+
+
+def test_in_progress_marker(tmp_path):
+    paths = Paths(tmp_path)
+    mark_in_progress(paths)
+    assert tmp_path.joinpath(".inprogress").exists()
+
+    clear_in_progress(paths)
+    assert not tmp_path.joinpath(".inprogress").exists()
+
+
+def test_confidence_histogram():
+    histogram = get_confidence_histogram(
+        {"q1": [1.0, 1.0, 0.5], "q2": [0.0, 1.0, 1.0]}, bins_count=4
+    )
+
+    assert histogram["bin_edges"] == [0.0, 0.25, 0.5, 0.75, 1.0]
+    assert histogram["counts"] == [1, 0, 1, 4]
+    assert histogram["fields"]["q1"]["mean"] == 0.8333
+    assert histogram["fields"]["q2"]["stddev"] == 0.4714
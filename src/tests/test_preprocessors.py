@@ -0,0 +1,262 @@
+import inspect
+import json
+from copy import deepcopy
+from pathlib import Path
+
+import cv2
+import numpy as np
+import pytest
+from dotmap import DotMap
+
+from src.core import ImageInstanceOps
+from src.defaults import CONFIG_DEFAULTS
+from src.entry import process_image, set_threads_count
+from src.logger import logger
+from src.processors.builtins import Morphology, Threshold
+from src.processors.manager import PROCESSOR_MANAGER
+from src.template import Template
+from src.tests.test_samples.sample1.boilerplate import TEMPLATE_BOILERPLATE
+from src.utils.image import ImageUtils
+
+
+SAMPLE_IMAGE_PATH = Path(__file__).parent.joinpath(
+    "test_samples", "sample1", "sample.png"
+)
+
+
+def get_template(tmp_path, pre_processors, tuning_config=CONFIG_DEFAULTS):
+    template_path = tmp_path.joinpath("template.json")
+    with open(template_path, "w") as f:
+        json.dump({**TEMPLATE_BOILERPLATE, "preProcessors": pre_processors}, f)
+    return Template(template_path, tuning_config)
+
+
+def get_noisy_image():
+    image = np.full((400, 300), 255, dtype=np.uint8)
+    # Salt and pepper noise which a median blur removes
+    image[::7, ::5] = 0
+    return image
+
+
+def apply_preprocessors(template, image):
+    return template.image_instance_ops.apply_preprocessors(
+        "noisy.png", image.copy(), template
+    )
+
+
+def test_template_median_blur_changes_pixels(tmp_path):
+    image = get_noisy_image()
+    plain_template = get_template(tmp_path, [])
+    blur_template = get_template(
+        tmp_path, [{"name": "MedianBlur", "options": {"kSize": 3}}]
+    )
+
+    plain_output = apply_preprocessors(plain_template, image)
+    blurred_output = apply_preprocessors(blur_template, image)
+
+    assert not np.array_equal(plain_output, blurred_output)
+    assert blurred_output.min() > plain_output.min()
+
+
+def get_diagonal_noise():
+    image = np.zeros((50, 50), dtype=np.uint8)
+    # A three pixels wide diagonal streak
+    for offset in [-1, 0, 1]:
+        np.fill_diagonal(image[max(0, offset) :, max(0, -offset) :], 255)
+    return image
+
+
+def open_with_kernel(image, kernel_shape):
+    morphology = Morphology(
+        options={"operation": "open", "kSize": [3, 3], "kernelShape": kernel_shape},
+        relative_dir=None,
+        image_instance_ops=ImageInstanceOps(CONFIG_DEFAULTS),
+    )
+    # Ignore the image borders which are not eroded
+    return morphology.apply_filter(image, "noise.png")[5:-5, 5:-5]
+
+
+def test_cross_kernel_keeps_diagonal_noise():
+    image = get_diagonal_noise()
+
+    assert np.array_equal(open_with_kernel(image, "cross"), image[5:-5, 5:-5])
+    assert not open_with_kernel(image, "rect").any()
+
+
+def test_template_threshold_binarizes(tmp_path):
+    image = np.tile(np.arange(0, 256, dtype=np.uint8), (400, 2))[:, :300]
+    template = get_template(
+        tmp_path, [{"name": "Threshold", "options": {"threshold": 100}}]
+    )
+
+    output = apply_preprocessors(template, image)
+
+    assert set(np.unique(output)) == {0, 255}
+
+
+def get_shaded_page():
+    # Paper shaded from 250 on the left to 100 on the right, with dark marks across
+    image = np.tile(np.linspace(250, 100, 300), (400, 1)).astype(np.uint8)
+    is_mark = np.zeros(image.shape, dtype=bool)
+    for x in range(20, 300, 50):
+        is_mark[190:210, x : x + 20] = True
+    image[is_mark] = 40
+    return image, is_mark
+
+
+def get_binarization_accuracy(method, image, is_mark):
+    threshold = Threshold(
+        options={"method": method},
+        relative_dir=None,
+        image_instance_ops=ImageInstanceOps(CONFIG_DEFAULTS),
+    )
+    output = threshold.apply_filter(image, "shaded.png")
+    return np.mean((output == 0) == is_mark)
+
+
+def test_sauvola_threshold_follows_shading():
+    image, is_mark = get_shaded_page()
+
+    otsu_accuracy = get_binarization_accuracy("otsu", image, is_mark)
+    sauvola_accuracy = get_binarization_accuracy("sauvola", image, is_mark)
+
+    # A global threshold blackens the darker half of the paper with the marks
+    assert otsu_accuracy < 0.8
+    assert sauvola_accuracy > 0.99
+
+
+@pytest.mark.parametrize("page_type", ["black", "dark"])
+def test_dark_page_reads_like_the_normal_sheet(tmp_path, page_type):
+    image = cv2.imread(str(SAMPLE_IMAGE_PATH), cv2.IMREAD_GRAYSCALE)
+    pre_processors = TEMPLATE_BOILERPLATE["preProcessors"]
+    dark_config = DotMap(deepcopy(CONFIG_DEFAULTS.toDict()), _dynamic=False)
+    dark_config.threshold_params.PAGE_TYPE_FOR_THRESHOLD = page_type
+
+    normal_response, *_ = process_image(
+        image, get_template(tmp_path, pre_processors), SAMPLE_IMAGE_PATH
+    )
+    dark_response, *_ = process_image(
+        cv2.bitwise_not(image),
+        get_template(tmp_path, pre_processors, dark_config),
+        SAMPLE_IMAGE_PATH,
+    )
+
+    assert dark_response == normal_response
+
+
+def test_single_thread_processing(tmp_path):
+    image = cv2.imread(str(SAMPLE_IMAGE_PATH), cv2.IMREAD_GRAYSCALE)
+    template = get_template(tmp_path, TEMPLATE_BOILERPLATE["preProcessors"])
+    default_threads = cv2.getNumThreads()
+    default_response, *_ = process_image(image, template, SAMPLE_IMAGE_PATH)
+
+    set_threads_count(1)
+    try:
+        assert cv2.getNumThreads() == 1
+        single_thread_response, *_ = process_image(image, template, SAMPLE_IMAGE_PATH)
+    finally:
+        cv2.setNumThreads(default_threads)
+
+    assert single_thread_response == default_response
+
+
+def test_tiny_image_is_reported_as_error(tmp_path):
+    image = cv2.imread(str(SAMPLE_IMAGE_PATH), cv2.IMREAD_GRAYSCALE)
+    thumbnail = cv2.resize(image, (60, 80))
+    template = get_template(tmp_path, TEMPLATE_BOILERPLATE["preProcessors"])
+
+    assert process_image(thumbnail, template, SAMPLE_IMAGE_PATH) is None
+
+
+def test_wrong_aspect_ratio_warning(tmp_path, mocker):
+    warning = mocker.patch.object(logger, "warning")
+    template = get_template(tmp_path, [])
+    # A landscape strip for a portrait template page
+    strip = np.full((300, 1200), 255, dtype=np.uint8)
+
+    process_image(strip, template, "strip.png")
+
+    assert template.image_instance_ops.get_aspect_deviation(strip, template) > 0.5
+    assert any("Aspect ratio" in call.args[0] for call in warning.call_args_list)
+
+
+def apply_clahe(tmp_path, image, clip_limit):
+    template = get_template(
+        tmp_path,
+        [
+            {
+                "name": "CLAHE",
+                "options": {"clipLimit": clip_limit, "tileGridSize": [4, 4]},
+            }
+        ],
+    )
+    # Bypass the resize to processing dimensions
+    return template.pre_processors[0].apply_filter(image, "gradient.png")
+
+
+def test_clahe_clip_limit(tmp_path):
+    gradient = np.tile(np.linspace(80, 160, 256).astype(np.uint8), (256, 1))
+
+    low_clip = apply_clahe(tmp_path, gradient, 1.0)
+    high_clip = apply_clahe(tmp_path, gradient, 40.0)
+
+    # A higher clip limit stretches the contrast further
+    assert np.ptp(high_clip) > np.ptp(low_clip) + 20
+    # The tile mappings are interpolated, without steps at the tile borders
+    column_steps = np.abs(np.diff(high_clip[128].astype(int)))
+    assert column_steps.max() < 32
+
+
+def test_contrast_stretch_ignores_a_speck(tmp_path):
+    # A washed out photo, with a single black speck
+    image = np.tile(np.linspace(100, 150, 200).astype(np.uint8), (200, 1))
+    image[100, 100] = 0
+    template = get_template(tmp_path, [{"name": "ContrastStretch", "options": {}}])
+
+    stretched = template.pre_processors[0].apply_filter(image, "photo.png")
+    min_max_stretched = ImageUtils.normalize_util(image)
+
+    # The speck already spans the min-max range, so the page stays washed out
+    assert np.ptp(min_max_stretched[50]) < 100
+    assert np.ptp(stretched[50]) > 230
+
+
+PLUGIN_SOURCE = """
+from src.processors.interfaces.ImagePreprocessor import ImagePreprocessor
+
+APPLIED_ORDER = []
+
+
+class StampCorner(ImagePreprocessor):
+    def apply_filter(self, image, filename):
+        # Records the pixel left by the previous step, then marks its own run
+        APPLIED_ORDER.append(int(image[0, 0]))
+        image[0, 0] = self.options["value"]
+        return image
+"""
+
+
+def test_custom_processors_run_in_order(tmp_path):
+    processors_dir = tmp_path.joinpath("processors")
+    processors_dir.mkdir()
+    processors_dir.joinpath("stamp.py").write_text(PLUGIN_SOURCE)
+    PROCESSOR_MANAGER.load_processors_from_dir(processors_dir)
+    try:
+        template = get_template(
+            tmp_path,
+            [
+                {"name": "StampCorner", "options": {"value": 10}},
+                {"name": "MedianBlur", "options": {"kSize": 3}},
+                {"name": "StampCorner", "options": {"value": 20}},
+            ],
+        )
+        output = apply_preprocessors(template, get_noisy_image())
+        plugin_module = inspect.getmodule(PROCESSOR_MANAGER.processors["StampCorner"])
+    finally:
+        PROCESSOR_MANAGER.processors.pop("StampCorner")
+
+    # The second stamp sees the corner after the median blur of the first one
+    assert len(plugin_module.APPLIED_ORDER) == 2
+    assert plugin_module.APPLIED_ORDER[1] != 10
+    assert output[0, 0] == 20
+
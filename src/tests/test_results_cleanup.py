@@ -0,0 +1,25 @@
+import os
+import time
+
+from app.results_cleanup import remove_expired_results
+
+
+def test_expired_results_are_removed(tmp_path):
+    now = time.time()
+    old_job_dir = tmp_path.joinpath("old_job")
+    old_job_dir.mkdir()
+    old_job_dir.joinpath("answers.csv").write_text("Question,Answer\n")
+    old_csv = tmp_path.joinpath("old.csv")
+    old_csv.write_text("Question,Answer\n")
+    fresh_csv = tmp_path.joinpath("fresh.csv")
+    fresh_csv.write_text("Question,Answer\n")
+    # Age the old entries past a one hour ttl
+    for old_path in [old_job_dir, old_csv]:
+        os.utime(old_path, (now - 7200, now - 7200))
+
+    removed = remove_expired_results(tmp_path, ttl_seconds=3600, now=now)
+
+    assert removed == ["old.csv", "old_job"]
+    assert not old_job_dir.exists()
+    assert fresh_csv.exists()
+    assert remove_expired_results(tmp_path.joinpath("missing"), 3600) == []
@@ -0,0 +1,377 @@
+import json
+
+import numpy as np
+import pytest
+
+from src.constants import TEMPLATE_SCHEMA_VERSION
+from src.defaults import CONFIG_DEFAULTS
+from src.entry import process_image, process_image_regions
+from src.evaluation import EvaluationConfig, evaluate_concatenated_response
+from src.exceptions import TemplateInvalidError
+from src.template import Template
+from src.utils.layout import render_template_to_image
+from src.utils.parsing import open_template_with_defaults
+
+BASE_TEMPLATE = {
+    "pageDimensions": [300, 400],
+    "bubbleDimensions": [25, 25],
+    "preProcessors": [],
+    "fieldBlocks": {
+        "MCQ_Block_1": {
+            "fieldType": "QTYPE_MCQ4",
+            "origin": [65, 60],
+            "fieldLabels": ["q1..5"],
+            "labelsGap": 52,
+            "bubblesGap": 41,
+        },
+        "Int_Block_1": {
+            "fieldType": "QTYPE_INT",
+            "origin": [65, 200],
+            "fieldLabels": ["q6"],
+            "labelsGap": 52,
+            "bubblesGap": 41,
+        },
+    },
+}
+
+
+def write_json(path, data):
+    with open(path, "w") as f:
+        json.dump(data, f)
+
+
+def test_two_level_template_inheritance(tmp_path):
+    write_json(tmp_path.joinpath("base.json"), BASE_TEMPLATE)
+    write_json(
+        tmp_path.joinpath("school.json"),
+        {
+            "extends": "base.json",
+            "customLabels": {"q1_2": ["q1", "q2"]},
+        },
+    )
+    tmp_path.joinpath("exam").mkdir()
+    write_json(
+        tmp_path.joinpath("exam", "template.json"),
+        {
+            "extends": "../school.json",
+            "bubbleDimensions": [20, 20],
+            "fieldBlocks": {
+                "MCQ_Block_1": {
+                    "fieldType": "QTYPE_MCQ5",
+                    "origin": [70, 60],
+                    "fieldLabels": ["q1..5"],
+                    "labelsGap": 52,
+                    "bubblesGap": 41,
+                },
+            },
+        },
+    )
+
+    template = open_template_with_defaults(tmp_path.joinpath("exam", "template.json"))
+
+    assert "extends" not in template
+    assert template["pageDimensions"] == [300, 400]
+    assert template["bubbleDimensions"] == [20, 20]
+    assert template["customLabels"] == {"q1_2": ["q1", "q2"]}
+    assert template["fieldBlocks"]["MCQ_Block_1"]["fieldType"] == "QTYPE_MCQ5"
+    assert template["fieldBlocks"]["MCQ_Block_1"]["origin"] == [70, 60]
+    assert template["fieldBlocks"]["Int_Block_1"]["fieldType"] == "QTYPE_INT"
+
+
+def test_circular_template_inheritance(tmp_path):
+    write_json(tmp_path.joinpath("a.json"), {**BASE_TEMPLATE, "extends": "b.json"})
+    write_json(tmp_path.joinpath("b.json"), {"extends": "a.json"})
+
+    with pytest.raises(Exception) as exception:
+        open_template_with_defaults(tmp_path.joinpath("a.json"))
+
+    assert str(exception.value).startswith("Circular 'extends' chain found")
+
+
+def test_two_column_template(tmp_path):
+    template_path = tmp_path.joinpath("template.json")
+    write_json(
+        template_path,
+        {
+            **BASE_TEMPLATE,
+            "pageDimensions": [600, 400],
+            "columns": [{"origin": [0, 0]}, {"origin": [300, 0]}],
+            "fieldBlocks": {
+                "MCQ_Block_1": {
+                    "fieldType": "QTYPE_MCQ4",
+                    "origin": [20, 60],
+                    "fieldLabels": ["q1..5"],
+                    "labelsGap": 52,
+                    "bubblesGap": 41,
+                },
+                "MCQ_Block_2": {
+                    "fieldType": "QTYPE_MCQ4",
+                    "origin": [20, 330],
+                    "fieldLabels": ["q6"],
+                    "labelsGap": 52,
+                    "bubblesGap": 41,
+                },
+            },
+        },
+    )
+
+    template = Template(template_path, CONFIG_DEFAULTS)
+
+    assert len(template.field_blocks) == 4
+    assert template.output_columns == [f"q{i}" for i in range(1, 13)]
+    right_block = next(
+        block for block in template.field_blocks if block.name == "MCQ_Block_1_col2"
+    )
+    assert right_block.origin == [320, 60]
+    assert right_block.traverse_bubbles[0][0].field_label == "q7"
+
+
+def test_field_block_bubble_dimensions(tmp_path):
+    template_path = tmp_path.joinpath("template.json")
+    write_json(
+        template_path,
+        {
+            **BASE_TEMPLATE,
+            "fieldBlocks": {
+                "MCQ_Block_1": BASE_TEMPLATE["fieldBlocks"]["MCQ_Block_1"],
+                "Roll_Block": {
+                    "fieldType": "QTYPE_INT",
+                    "bubbleDimensions": [12, 12],
+                    "origin": [230, 150],
+                    "fieldLabels": ["roll1..2"],
+                    "labelsGap": 20,
+                    "bubblesGap": 20,
+                },
+            },
+        },
+    )
+    template = Template(template_path, CONFIG_DEFAULTS)
+    marks = {"q1": "B", "q2": "A", "q3": "D", "q4": "C", "q5": "A"}
+    marks.update({"roll1": "7", "roll2": "3"})
+
+    omr_response, *_ = process_image(
+        render_template_to_image(template, marks), template, "sheet.png"
+    )
+
+    assert [block.bubble_dimensions for block in template.field_blocks] == [
+        [25, 25],
+        [12, 12],
+    ]
+    assert {label: omr_response[label] for label in marks} == marks
+
+
+def test_rotated_field_block(tmp_path):
+    template_path = tmp_path.joinpath("template.json")
+    mcq_block = BASE_TEMPLATE["fieldBlocks"]["MCQ_Block_1"]
+    write_json(
+        template_path,
+        {**BASE_TEMPLATE, "fieldBlocks": {"MCQ_Block_1": {**mcq_block, "rotation": 5}}},
+    )
+    template = Template(template_path, CONFIG_DEFAULTS)
+    marks = {"q1": "D", "q2": "C", "q3": "B", "q4": "A", "q5": "D"}
+
+    omr_response, *_ = process_image(
+        render_template_to_image(template, marks), template, "sheet.png"
+    )
+
+    q1_bubbles = template.field_blocks[0].traverse_bubbles[0]
+    # The row drops by 123 * sin(5 degrees) from its first to its last bubble
+    assert (q1_bubbles[0].x, q1_bubbles[0].y) == (65, 60)
+    assert (q1_bubbles[-1].x, q1_bubbles[-1].y) == (188, 71)
+    assert {label: omr_response[label] for label in marks} == marks
+
+
+def test_value_map_translates_numeric_bubbles(tmp_path):
+    template_path = tmp_path.joinpath("template.json")
+    mcq_block = BASE_TEMPLATE["fieldBlocks"]["MCQ_Block_1"]
+    numeric_block = {
+        key: value for key, value in mcq_block.items() if key != "fieldType"
+    }
+    write_json(
+        template_path,
+        {
+            **BASE_TEMPLATE,
+            "valueMap": {"1": "A", "2": "B", "3": "C", "4": "D"},
+            "fieldBlocks": {
+                "MCQ_Block_1": {
+                    **numeric_block,
+                    "bubbleValues": ["1", "2", "3", "4"],
+                    "direction": "horizontal",
+                }
+            },
+        },
+    )
+    evaluation_path = tmp_path.joinpath("evaluation.json")
+    write_json(
+        evaluation_path,
+        {
+            "source_type": "custom",
+            "options": {
+                "questions_in_order": ["q1..5"],
+                "answers_in_order": ["B", "A", "D", "C", "A"],
+            },
+            "marking_schemes": {
+                "DEFAULT": {"correct": 4, "incorrect": -1, "unmarked": 0}
+            },
+        },
+    )
+    template = Template(template_path, CONFIG_DEFAULTS)
+    evaluation_config = EvaluationConfig(
+        tmp_path, evaluation_path, template, CONFIG_DEFAULTS
+    )
+    # The printed bubbles 2, 1, 4, 3 and 1 are marked
+    marks = {"q1": "B", "q2": "A", "q3": "D", "q4": "C", "q5": "A"}
+
+    omr_response, *_ = process_image(
+        render_template_to_image(template, marks), template, "sheet.png"
+    )
+    score = evaluate_concatenated_response(
+        omr_response, evaluation_config, tmp_path.joinpath("sheet.png"), tmp_path
+    )
+
+    assert {label: omr_response[label] for label in marks} == marks
+    assert score == 20
+
+
+def test_two_student_regions(tmp_path):
+    template_path = tmp_path.joinpath("template.json")
+    write_json(
+        template_path,
+        {
+            **BASE_TEMPLATE,
+            "pageDimensions": [600, 400],
+            "fieldBlocks": {
+                "MCQ_Block_1": {
+                    **BASE_TEMPLATE["fieldBlocks"]["MCQ_Block_1"],
+                    "origin": [30, 60],
+                }
+            },
+            "studentRegions": [{"origin": [0, 0]}, {"origin": [300, 0]}],
+        },
+    )
+    template = Template(template_path, CONFIG_DEFAULTS)
+    first_marks = {"q1": "A", "q2": "B", "q3": "C", "q4": "D", "q5": "A"}
+    second_marks = {"q1": "D", "q2": "C", "q3": "B", "q4": "A", "q5": "D"}
+    first_region, second_region = template.student_region_templates
+    # Both students on one page
+    sheet = np.minimum(
+        render_template_to_image(first_region, first_marks),
+        render_template_to_image(second_region, second_marks),
+    )
+
+    region_detections = process_image_regions(sheet, template, "sheet.png")
+
+    assert [file_id for file_id, _ in region_detections] == [
+        "sheet_region1.png",
+        "sheet_region2.png",
+    ]
+    (_, (first_response, *_)), (_, (second_response, *_)) = region_detections
+    assert {label: first_response[label] for label in first_marks} == first_marks
+    assert {label: second_response[label] for label in second_marks} == second_marks
+
+
+@pytest.mark.parametrize(
+    "digits,expected_date,is_flagged",
+    [("25122001", "2001-12-25", False), ("25132001", "25/13/2001", True)],
+)
+def test_date_grid(tmp_path, digits, expected_date, is_flagged):
+    template_path = tmp_path.joinpath("template.json")
+    write_json(
+        template_path,
+        {
+            "pageDimensions": [300, 400],
+            "bubbleDimensions": [20, 20],
+            "preProcessors": [],
+            "fieldBlocks": {
+                "Date_Block": {
+                    "fieldType": "QTYPE_INT",
+                    "origin": [40, 60],
+                    "fieldLabels": ["dob1..8"],
+                    "bubblesGap": 25,
+                    "labelsGap": 25,
+                }
+            },
+            "dateLabels": {
+                "dob": {"day": ["dob1..2"], "month": ["dob3..4"], "year": ["dob5..8"]}
+            },
+        },
+    )
+    template = Template(template_path, CONFIG_DEFAULTS)
+    marks = {f"dob{index + 1}": digit for index, digit in enumerate(digits)}
+
+    omr_response, _, _, omr_metrics = process_image(
+        render_template_to_image(template, marks), template, "sheet.png"
+    )
+
+    assert omr_response["dob"] == expected_date
+    assert ("dob" in omr_metrics["needs_review"]) == is_flagged
+
+
+def get_lint_categories(tmp_path, template_json):
+    write_json(tmp_path.joinpath("template.json"), template_json)
+    template = Template(
+        tmp_path.joinpath("template.json"), CONFIG_DEFAULTS, validate_bounds=False
+    )
+    return {category for category, _ in template.lint()}
+
+
+def with_mcq_block(**mcq_block_changes):
+    mcq_block = {**BASE_TEMPLATE["fieldBlocks"]["MCQ_Block_1"], **mcq_block_changes}
+    if "bubbleValues" in mcq_block_changes:
+        mcq_block.pop("fieldType")
+        mcq_block["direction"] = "horizontal"
+    return {**BASE_TEMPLATE, "fieldBlocks": {"MCQ_Block_1": mcq_block}}
+
+
+def test_clean_template_has_no_lint_warnings(tmp_path):
+    assert get_lint_categories(tmp_path, with_mcq_block()) == set()
+
+
+@pytest.mark.parametrize(
+    "template_json,category",
+    [
+        (with_mcq_block(bubblesGap=10), "overlapping_bubbles"),
+        (with_mcq_block(bubbleValues=["A", "B", "B", "D"]), "duplicate_values"),
+        (with_mcq_block(valueMap={"A": "1", "B": "2"}), "bubble_count"),
+        (
+            # Five bubbleValues next to a fieldType that only has four bubbles
+            {
+                **BASE_TEMPLATE,
+                "fieldBlocks": {
+                    "MCQ_Block_1": {
+                        **BASE_TEMPLATE["fieldBlocks"]["MCQ_Block_1"],
+                        "bubbleValues": ["A", "B", "C", "D", "E"],
+                    }
+                },
+            },
+            "bubble_count",
+        ),
+        (with_mcq_block(origin=[200, 60]), "outside_page"),
+    ],
+)
+def test_lint_warnings(tmp_path, template_json, category):
+    assert get_lint_categories(tmp_path, template_json) == {category}
+
+
+def test_version_1_template_is_migrated(tmp_path):
+    # BASE_TEMPLATE has no schemaVersion, as written before versioning
+    write_json(tmp_path.joinpath("template.json"), BASE_TEMPLATE)
+
+    template = open_template_with_defaults(tmp_path.joinpath("template.json"))
+
+    assert template["schemaVersion"] == TEMPLATE_SCHEMA_VERSION
+    assert template["referenceStrips"] == []
+    assert template["valueMap"] == {}
+    assert template["fieldBlocks"] == BASE_TEMPLATE["fieldBlocks"]
+
+
+def test_future_template_version_is_rejected(tmp_path):
+    write_json(
+        tmp_path.joinpath("template.json"),
+        {**BASE_TEMPLATE, "schemaVersion": TEMPLATE_SCHEMA_VERSION + 1},
+    )
+
+    with pytest.raises(TemplateInvalidError) as exception:
+        open_template_with_defaults(tmp_path.joinpath("template.json"))
+
+    assert "Unsupported schemaVersion" in str(exception.value)
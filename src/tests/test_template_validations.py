@@ -1,6 +1,11 @@
+import json
 import os
 from pathlib import Path
 
+from jsonschema import Draft202012Validator
+
+from src.constants import FIELD_TYPES
+from src.schemas import get_schema_json
 from src.tests.test_samples.sample1.boilerplate import TEMPLATE_BOILERPLATE
 from src.tests.utils import (
     generate_write_jsons_and_run,
@@ -75,7 +80,40 @@ def test_overflow_labels(mocker):
     exception = write_jsons_and_run(mocker, modify_template=modify_template)
     assert (
         str(exception)
-        == "Overflowing field block 'MCQ_Block_1' with origin [65, 60] and dimensions [189, 5173] in template with dimensions [300, 400]"
+        == "Overflowing bubble 0 of field 'q8' in field block 'MCQ_Block_1' with position [65, 424] and dimensions [25, 25] in template with dimensions [300, 400]"
+    )
+
+
+def test_overflow_bubbles(mocker):
+    def modify_template(template):
+        template["fieldBlocks"]["MCQ_Block_1"]["bubblesGap"] = 60
+
+    exception = write_jsons_and_run(mocker, modify_template=modify_template)
+    assert (
+        str(exception)
+        == "Overflowing bubble 4 of field 'q1' in field block 'MCQ_Block_1' with position [305, 60] and dimensions [25, 25] in template with dimensions [300, 400]"
+    )
+
+
+def test_zero_field_block_bubble_dimensions(mocker):
+    def modify_template(template):
+        template["fieldBlocks"]["MCQ_Block_1"]["bubbleDimensions"] = [0, 25]
+
+    exception = write_jsons_and_run(mocker, modify_template=modify_template)
+    assert (
+        str(exception)
+        == f"Provided Template JSON is Invalid: '{BASE_SAMPLE_TEMPLATE_PATH}'"
+    )
+
+
+def test_overflow_field_block_bubble_dimensions(mocker):
+    def modify_template(template):
+        template["fieldBlocks"]["MCQ_Block_1"]["bubbleDimensions"] = [80, 25]
+
+    exception = write_jsons_and_run(mocker, modify_template=modify_template)
+    assert (
+        str(exception)
+        == "Overflowing bubble 4 of field 'q1' in field block 'MCQ_Block_1' with position [229, 60] and dimensions [80, 25] in template with dimensions [300, 400]"
     )
 
 
@@ -157,3 +195,15 @@ def test_safe_missing_label_columns(mocker):
 
     exception = write_jsons_and_run(mocker, modify_template=modify_template)
     assert str(exception) == "No Error"
+
+
+def test_emitted_schema_validates_known_good_template():
+    schema = json.loads(get_schema_json("template"))
+    validator = Draft202012Validator(schema)
+
+    assert list(validator.iter_errors(TEMPLATE_BOILERPLATE)) == []
+    field_block_schemas = schema["properties"]["fieldBlocks"]["patternProperties"]
+    assert any(
+        sorted(field_block["properties"]["fieldType"]["enum"]) == sorted(FIELD_TYPES)
+        for field_block in field_block_schemas.values()
+    )
@@ -0,0 +1,65 @@
+import io
+import zipfile
+
+import pytest
+
+from app.upload_guard import (
+    UploadRejected,
+    check_request_size,
+    check_upload,
+    extract_zip_images,
+    sanitize_filename,
+)
+
+PNG_BYTES = b"\x89PNG\r\n\x1a\n" + b"\x00" * 100
+
+
+def test_traversal_filename_is_reduced_to_basename():
+    assert sanitize_filename("../../x") == "x"
+    assert sanitize_filename("..\\..\\etc\\sheet.png") == "sheet.png"
+    assert sanitize_filename("../..") == "upload"
+    assert check_upload("../../sheet.png", PNG_BYTES) == "sheet.png"
+
+
+def test_oversized_upload_is_rejected():
+    with pytest.raises(UploadRejected) as error:
+        check_upload("sheet.png", PNG_BYTES, max_file_bytes=50)
+    assert error.value.status == 413
+
+    with pytest.raises(UploadRejected) as error:
+        check_request_size([60, 60], max_request_bytes=100)
+    assert error.value.status == 413
+
+
+def test_non_image_upload_is_rejected():
+    with pytest.raises(UploadRejected) as error:
+        check_upload("sheet.png", b"<html></html>")
+    assert error.value.status == 415
+
+
+def make_zip(entries):
+    buffer = io.BytesIO()
+    with zipfile.ZipFile(buffer, "w") as archive:
+        for name, data in entries:
+            archive.writestr(name, data)
+    return buffer.getvalue()
+
+
+def test_zip_upload_extracts_images_only():
+    data = make_zip(
+        [
+            ("batch/sheet1.png", PNG_BYTES),
+            ("sheet2.jpg", b"\xff\xd8\xff" + b"\x00" * 100),
+            ("notes.txt", b"not an image"),
+            ("../../etc/evil.png", PNG_BYTES),
+        ]
+    )
+
+    images, rejected = extract_zip_images(data)
+
+    assert [name for name, _ in images] == ["sheet1.png", "sheet2.jpg"]
+    assert rejected == ["../../etc/evil.png"]
+
+    with pytest.raises(UploadRejected) as error:
+        extract_zip_images(data, max_extracted_bytes=150)
+    assert error.value.status == 413
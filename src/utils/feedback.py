@@ -0,0 +1,72 @@
+"""
+
+ OMRChecker
+
+ Author: Udayraj Deshmukh
+ Github: https://github.com/Udayraj123
+
+"""
+from matplotlib.backends.backend_pdf import PdfPages
+from matplotlib.figure import Figure
+
+from src.logger import logger
+
+# A4 portrait, in inches
+PAGE_SIZE = (8.27, 11.69)
+ROWS_PER_PAGE = 35
+VERDICT_COLORS = {"correct": "#d4edda", "incorrect": "#f8d7da", "unmarked": "#ffffff"}
+COLUMNS = ["Question", "Marked", "Answer(s)", "Verdict", "Marks"]
+
+
+def get_verdict_color(verdict):
+    # Verdicts of multiple correct answers look like 'correct-AB'
+    return VERDICT_COLORS.get(verdict.split("-")[0], VERDICT_COLORS["unmarked"])
+
+
+def write_feedback_pdf(
+    output_path, file_name, score, max_score, grade, question_results
+):
+    summary = [
+        f"File: {file_name}",
+        f"Score: {round(score, 2)} / {round(max_score, 2)}",
+    ]
+    if max_score > 0:
+        summary.append(f"Percentage: {round(100 * score / max_score, 2)}%")
+    if grade is not None:
+        summary.append(f"Grade: {grade}")
+
+    pages = [
+        question_results[start : start + ROWS_PER_PAGE]
+        for start in range(0, max(len(question_results), 1), ROWS_PER_PAGE)
+    ]
+    logger.info(f"Saving feedback sheet to '{output_path}'")
+    with PdfPages(output_path) as pdf:
+        for page_number, page_results in enumerate(pages):
+            figure = Figure(figsize=PAGE_SIZE)
+            axes = figure.add_subplot()
+            axes.axis("off")
+            if page_number == 0:
+                axes.set_title("\n".join(summary), loc="left", fontsize=11)
+            if len(page_results) > 0:
+                rows = [
+                    [
+                        question,
+                        marked_answer,
+                        answer,
+                        verdict.title(),
+                        str(round(delta, 2)),
+                    ]
+                    for question, marked_answer, answer, verdict, delta in page_results
+                ]
+                table = axes.table(
+                    cellText=rows,
+                    colLabels=COLUMNS,
+                    cellColours=[
+                        [get_verdict_color(row_result[3])] * len(COLUMNS)
+                        for row_result in page_results
+                    ],
+                    loc="upper center",
+                )
+                table.auto_set_font_size(False)
+                table.set_fontsize(9)
+            pdf.savefig(figure)
@@ -1,12 +1,23 @@
 import argparse
+import hashlib
 import json
 import os
 from csv import QUOTE_NONNUMERIC
+from pathlib import Path
 from time import localtime, strftime
 
+import cv2
+import numpy as np
 import pandas as pd
 
+from src.defaults import CONFIG_DEFAULTS
+from src.exceptions import JsonParseError
 from src.logger import logger
+from src.utils.image import ImageUtils
+from src.utils.layout import get_bubble_mark_counts, render_mark_heatmap
+
+# Difference hashes within this many bits are taken to be the same scan
+MAX_PERCEPTUAL_HASH_DISTANCE = 4
 
 
 def load_json(path, **rest):
@@ -15,7 +26,7 @@ def load_json(path, **rest):
             loaded = json.load(f, **rest)
     except json.decoder.JSONDecodeError as error:
         logger.critical(f"Error when loading json file at: '{path}'\n{error}")
-        exit(1)
+        raise JsonParseError(f"Error when loading json file at: '{path}'") from None
     return loaded
 
 
@@ -28,6 +39,13 @@ class Paths:
         self.evaluation_dir = output_dir.joinpath("Evaluation")
         self.errors_dir = self.manual_dir.joinpath("ErrorFiles")
         self.multi_marked_dir = self.manual_dir.joinpath("MultiMarkedFiles")
+        self.rejected_dir = self.manual_dir.joinpath("RejectedFiles")
+        self.watch_manifest_path = output_dir.joinpath("WatchedFiles.json")
+        # The files a run has completed, one json line each, for --resume
+        self.progress_path = output_dir.joinpath(".progress")
+        self.outputs_manifest_path = output_dir.joinpath("manifest.json")
+        # Present while a run is writing to output_dir, left behind by a crashed run
+        self.in_progress_marker_path = output_dir.joinpath(".inprogress")
 
 
 def setup_dirs_for_paths(paths):
@@ -51,22 +69,65 @@ def setup_dirs_for_paths(paths):
             os.makedirs(save_output_dir)
 
 
-def setup_outputs_for_template(paths, template):
+# The outputs namespace lists that --resume restores from the progress file
+RESUMABLE_RECORD_LISTS = [
+    "OUTPUT_SET",
+    "evaluation_records",
+    "gradebook_records",
+    "rejected_files",
+    "marked_bubbles",
+]
+
+
+def setup_outputs_for_template(
+    paths, template, tuning_config=CONFIG_DEFAULTS, write_files=True
+):
     # TODO: consider moving this into a class instance
     ns = argparse.Namespace()
     logger.info("Checking Files...")
 
     # Include current output paths
     ns.paths = paths
+    ns.csv_delimiter = tuning_config.outputs.csv_delimiter
+    ns.decimal_comma = tuning_config.outputs.decimal_comma
 
     ns.empty_resp = [""] * len(template.output_columns)
-    ns.sheetCols = [
-        "file_id",
-        "input_path",
-        "output_path",
-        "score",
-    ] + template.output_columns
+    # Per file detection metrics, appended after the response columns
+    ns.metricsCols = [
+        "file_confidence",
+        "multi_marked",
+        "is_blank",
+        "needs_review",
+        # Low values point to a blurred or washed out scan
+        "blur_score",
+        "contrast_score",
+        # Fields overridden by corrections.json
+        "manually_corrected",
+    ]
+    ns.empty_metrics = [""] * len(ns.metricsCols)
+    ns.sheetCols = (
+        [
+            "file_id",
+            "input_path",
+            "output_path",
+            "score",
+        ]
+        + template.output_columns
+        + ns.metricsCols
+    )
     ns.OUTPUT_SET = []
+    # Field label -> confidences across all the processed files
+    ns.field_confidences = {}
+    # (question verdicts, score) of each evaluated file
+    ns.evaluation_records = []
+    # (roll number, question marks, score) of each evaluated file
+    ns.gradebook_records = []
+    # Number of sheets on which each question was left blank
+    ns.omitted_counts = {}
+    # (file id, reason) of the sheets rejected for a re-scan
+    ns.rejected_files = []
+    # Field label -> marked values of each file, for the mark heatmap
+    ns.marked_bubbles = []
     ns.files_obj = {}
     TIME_NOW_HRS = strftime("%I%p", localtime())
     ns.filesMap = {
@@ -74,12 +135,25 @@ def setup_outputs_for_template(paths, template):
         "MultiMarked": os.path.join(paths.manual_dir, "MultiMarkedFiles.csv"),
         "Errors": os.path.join(paths.manual_dir, "ErrorFiles.csv"),
     }
+    if tuning_config.outputs.reject_below_confidence > 0:
+        ns.filesMap["Rejected"] = os.path.join(paths.manual_dir, "RejectedFiles.csv")
+    ns.xlsx_path = os.path.join(paths.results_dir, f"Results_{TIME_NOW_HRS}.xlsx")
+    ns.confidence_histogram_path = os.path.join(
+        paths.results_dir, "confidence_histogram.json"
+    )
+    ns.mark_heatmap_path = os.path.join(paths.results_dir, "mark_heatmap.png")
+    ns.mark_counts_path = os.path.join(paths.results_dir, "mark_counts.csv")
+    ns.item_analysis_path = os.path.join(paths.evaluation_dir, "item_analysis.csv")
+    ns.gradebook_path = os.path.join(paths.evaluation_dir, "gradebook.csv")
+    if not write_files:
+        # The records stay in memory for the ndjson output
+        return ns
 
     for file_key, file_name in ns.filesMap.items():
+        # moved handling of files to pandas csv writer
+        ns.files_obj[file_key] = file_name
         if not os.path.exists(file_name):
             logger.info(f"Created new file: '{file_name}'")
-            # moved handling of files to pandas csv writer
-            ns.files_obj[file_key] = file_name
             # Create Header Columns
             pd.DataFrame([ns.sheetCols], dtype=str).to_csv(
                 ns.files_obj[file_key],
@@ -87,9 +161,374 @@ def setup_outputs_for_template(paths, template):
                 quoting=QUOTE_NONNUMERIC,
                 header=False,
                 index=False,
+                sep=ns.csv_delimiter,
             )
         else:
             logger.info(f"Present : appending to '{file_name}'")
-            ns.files_obj[file_key] = open(file_name, "a")
 
     return ns
+
+
+def write_atomically(path, write):
+    """
+    Calls write with a temporary path next to path and then renames it over
+        path, so that a killed run never leaves a partially written file.
+    """
+    path = Path(path)
+    # Keeps the extension, pandas picks the file format by it
+    temp_path = path.with_name(f"{path.stem}.tmp{path.suffix}")
+    try:
+        write(temp_path)
+        os.replace(temp_path, path)
+    finally:
+        if temp_path.exists():
+            os.remove(temp_path)
+
+
+def mark_in_progress(paths):
+    paths.in_progress_marker_path.touch()
+
+
+def clear_in_progress(paths):
+    if paths.in_progress_marker_path.exists():
+        os.remove(paths.in_progress_marker_path)
+
+
+def format_decimal_comma(value):
+    return str(value).replace(".", ",") if isinstance(value, float) else value
+
+
+def append_results_row(outputs_namespace, file_key, row):
+    # Appends one file's row to the Results, MultiMarked or Errors csv
+    if outputs_namespace.decimal_comma:
+        row = [format_decimal_comma(value) for value in row]
+    pd.DataFrame(row, dtype=str).T.to_csv(
+        outputs_namespace.files_obj[file_key],
+        mode="a",
+        quoting=QUOTE_NONNUMERIC,
+        header=False,
+        index=False,
+        sep=outputs_namespace.csv_delimiter,
+    )
+
+
+def get_confidence_histogram(field_confidences, bins_count=20):
+    all_confidences = [
+        confidence
+        for confidences in field_confidences.values()
+        for confidence in confidences
+    ]
+    counts, bin_edges = np.histogram(all_confidences, bins=bins_count, range=(0, 1))
+    return {
+        "bin_edges": [round(float(edge), 2) for edge in bin_edges],
+        "counts": counts.tolist(),
+        "fields": {
+            field_label: {
+                "mean": round(float(np.mean(confidences)), 4),
+                "stddev": round(float(np.std(confidences)), 4),
+            }
+            for field_label, confidences in field_confidences.items()
+        },
+    }
+
+
+def get_histogram_bar_chart(histogram, width=40):
+    max_count = max(max(histogram["counts"]), 1)
+    lines = []
+    for low, high, count in zip(
+        histogram["bin_edges"], histogram["bin_edges"][1:], histogram["counts"]
+    ):
+        bar = "#" * round(width * count / max_count)
+        lines.append(f"{low:.2f}-{high:.2f} | {bar} {count}")
+    return "\n".join(lines)
+
+
+def write_confidence_histogram(outputs_namespace):
+    histogram = get_confidence_histogram(outputs_namespace.field_confidences)
+    histogram_path = outputs_namespace.confidence_histogram_path
+    logger.info(f"Saving confidence histogram to '{histogram_path}'")
+
+    def write_histogram(temp_path):
+        with open(temp_path, "w") as f:
+            json.dump(histogram, f, indent=4)
+
+    write_atomically(histogram_path, write_histogram)
+    logger.debug(f"Field confidences:\n{get_histogram_bar_chart(histogram)}")
+
+
+def write_mark_heatmap(outputs_namespace, template):
+    files_count = len(outputs_namespace.marked_bubbles)
+    mark_counts = get_bubble_mark_counts(template, outputs_namespace.marked_bubbles)
+    heatmap_path = outputs_namespace.mark_heatmap_path
+    logger.info(f"Saving mark heatmap of {files_count} files to '{heatmap_path}'")
+    heatmap = render_mark_heatmap(template, mark_counts, files_count)
+    write_atomically(
+        heatmap_path, lambda temp_path: cv2.imwrite(str(temp_path), heatmap)
+    )
+    mark_counts_sheet = pd.DataFrame(
+        [
+            [field_label, field_value, count, round(count / max(files_count, 1), 4)]
+            for field_label, value_counts in mark_counts.items()
+            for field_value, count in value_counts.items()
+        ],
+        columns=["field_label", "bubble_value", "mark_count", "mark_frequency"],
+    )
+    write_atomically(
+        outputs_namespace.mark_counts_path,
+        lambda temp_path: mark_counts_sheet.to_csv(
+            temp_path, quoting=QUOTE_NONNUMERIC, index=False
+        ),
+    )
+
+
+def write_item_analysis(outputs_namespace, item_analysis):
+    item_analysis_path = outputs_namespace.item_analysis_path
+    logger.info(f"Saving item analysis to '{item_analysis_path}'")
+    item_analysis_sheet = pd.DataFrame.from_dict(
+        item_analysis, orient="index"
+    ).rename_axis("question")
+    write_atomically(
+        item_analysis_path,
+        lambda temp_path: item_analysis_sheet.to_csv(
+            temp_path, quoting=QUOTE_NONNUMERIC
+        ),
+    )
+
+
+def write_gradebook(outputs_namespace, gradebook):
+    gradebook_path = outputs_namespace.gradebook_path
+    logger.info(f"Saving gradebook of {len(gradebook)} students to '{gradebook_path}'")
+    write_atomically(
+        gradebook_path,
+        lambda temp_path: gradebook.to_csv(temp_path, quoting=QUOTE_NONNUMERIC),
+    )
+
+
+def get_config_hash(tuning_config):
+    config_json = json.dumps(tuning_config.toDict(), sort_keys=True, default=str)
+    return hashlib.sha256(config_json.encode()).hexdigest()
+
+
+def get_outputs_manifest(paths, template, tuning_config):
+    # The files written by a run, with paths relative to its output directory
+    output_files = sorted(
+        file_path
+        for output_dir in [
+            paths.save_marked_dir,
+            paths.results_dir,
+            paths.manual_dir,
+            paths.evaluation_dir,
+        ]
+        for file_path in Path(output_dir).glob("**/*")
+        if file_path.is_file()
+    )
+    return {
+        "template_path": str(template.path),
+        "config_hash": get_config_hash(tuning_config),
+        "files": [
+            {
+                "path": file_path.relative_to(paths.output_dir).as_posix(),
+                "bytes": file_path.stat().st_size,
+            }
+            for file_path in output_files
+        ],
+    }
+
+
+def write_outputs_manifest(outputs_namespace, template, tuning_config):
+    paths = outputs_namespace.paths
+    manifest = get_outputs_manifest(paths, template, tuning_config)
+    logger.info(
+        f"Saving a manifest of {len(manifest['files'])} output files to '{paths.outputs_manifest_path}'"
+    )
+
+    def write_manifest(temp_path):
+        with open(temp_path, "w") as f:
+            json.dump(manifest, f, indent=4)
+
+    write_atomically(paths.outputs_manifest_path, write_manifest)
+
+
+def write_aggregate_results(output_dir, csv_delimiter=","):
+    # Only the hourly results files, not other csv files saved next to them
+    results_paths = sorted(output_dir.glob("**/Results/Results_[0-9][0-9][AP]M.csv"))
+    if len(results_paths) == 0:
+        return
+    all_results = []
+    for results_path in results_paths:
+        results = pd.read_csv(
+            results_path, dtype=str, keep_default_na=False, sep=csv_delimiter
+        )
+        # The sub-directory of the input the results belong to
+        batch = results_path.parent.parent.relative_to(output_dir)
+        results.insert(0, "batch", str(batch))
+        all_results.append(results)
+
+    aggregate_results_path = output_dir.joinpath("AggregateResults.csv")
+    logger.info(f"Saving aggregate results to '{aggregate_results_path}'")
+    aggregate_results = pd.concat(all_results, ignore_index=True)
+    write_atomically(
+        aggregate_results_path,
+        lambda temp_path: aggregate_results.to_csv(
+            temp_path, quoting=QUOTE_NONNUMERIC, index=False, sep=csv_delimiter
+        ),
+    )
+
+
+def get_exact_hash(file_path):
+    with open(file_path, "rb") as f:
+        return hashlib.sha256(f.read()).hexdigest()
+
+
+def find_duplicate_files(omr_files, mode):
+    """
+    mode: "exact" compares the file bytes, "perceptual" compares the difference
+        hashes of the images to also catch re-encoded copies of a scan.
+    Returns the unique files and a map of duplicate -> canonical (first seen) file
+    """
+    unique_files, duplicates, seen_hashes = [], {}, []
+    for file_path in omr_files:
+        if mode == "exact":
+            file_hash = get_exact_hash(file_path)
+            canonical = next((f for h, f in seen_hashes if h == file_hash), None)
+        else:
+            image = ImageUtils.read_image_grayscale(str(file_path))
+            file_hash = ImageUtils.get_difference_hash(image)
+            canonical = next(
+                (
+                    f
+                    for h, f in seen_hashes
+                    if bin(h ^ file_hash).count("1") <= MAX_PERCEPTUAL_HASH_DISTANCE
+                ),
+                None,
+            )
+        if canonical is not None:
+            duplicates[file_path] = canonical
+        else:
+            seen_hashes.append((file_hash, file_path))
+            unique_files.append(file_path)
+    return unique_files, duplicates
+
+
+def load_watch_manifest(paths):
+    # Input path -> modification time of the files processed in watch mode
+    if not os.path.exists(paths.watch_manifest_path):
+        return {}
+    return load_json(paths.watch_manifest_path)
+
+
+def save_watch_manifest(paths, watch_manifest):
+    # Also kept when only ndjson is written and no other outputs are set up
+    os.makedirs(paths.output_dir, exist_ok=True)
+    with open(paths.watch_manifest_path, "w") as f:
+        json.dump(watch_manifest, f, indent=4)
+
+
+def load_progress(paths):
+    # Input path -> modification time and aggregate records of the files completed
+    #  by the previous run
+    progress = {}
+    if not os.path.exists(paths.progress_path):
+        return progress
+    with open(paths.progress_path, "r") as f:
+        for line in f:
+            try:
+                record = json.loads(line)
+            except json.decoder.JSONDecodeError:
+                # A line cut short by the interruption
+                continue
+            progress[record["path"]] = record
+    return progress
+
+
+def append_progress(paths, file_path, records):
+    with open(paths.progress_path, "a") as f:
+        f.write(
+            json.dumps(
+                {
+                    "path": str(file_path),
+                    "mtime": file_path.stat().st_mtime,
+                    "records": records,
+                },
+                # Numpy scalars in the scores and confidences
+                default=lambda value: value.item(),
+            )
+            + "\n"
+        )
+
+
+def count_records(outputs_namespace):
+    # Sizes of the aggregate records, to tell apart the records each file adds
+    return {
+        **{
+            key: len(getattr(outputs_namespace, key))
+            for key in RESUMABLE_RECORD_LISTS
+        },
+        "field_confidences": {
+            field_label: len(confidences)
+            for field_label, confidences in outputs_namespace.field_confidences.items()
+        },
+        "omitted_counts": dict(outputs_namespace.omitted_counts),
+    }
+
+
+def get_added_records(outputs_namespace, records_count):
+    added_records = {
+        key: getattr(outputs_namespace, key)[records_count[key] :]
+        for key in RESUMABLE_RECORD_LISTS
+    }
+    added_records["field_confidences"] = {
+        field_label: confidences[
+            records_count["field_confidences"].get(field_label, 0) :
+        ]
+        for field_label, confidences in outputs_namespace.field_confidences.items()
+    }
+    added_records["omitted_counts"] = {
+        question: count - records_count["omitted_counts"].get(question, 0)
+        for question, count in outputs_namespace.omitted_counts.items()
+    }
+    return added_records
+
+
+def restore_records(outputs_namespace, added_records):
+    for key in RESUMABLE_RECORD_LISTS:
+        getattr(outputs_namespace, key).extend(added_records[key])
+    for field_label, confidences in added_records["field_confidences"].items():
+        outputs_namespace.field_confidences.setdefault(field_label, []).extend(
+            confidences
+        )
+    for question, count in added_records["omitted_counts"].items():
+        outputs_namespace.omitted_counts[question] = (
+            outputs_namespace.omitted_counts.get(question, 0) + count
+        )
+
+
+def clear_progress(paths):
+    if os.path.exists(paths.progress_path):
+        os.remove(paths.progress_path)
+
+
+def write_results_xlsx(outputs_namespace, template):
+    # One row per file and one column per output field, in outputs order
+    results_sheet = pd.DataFrame(
+        outputs_namespace.OUTPUT_SET,
+        columns=["file_id"] + template.output_columns + ["file_confidence"],
+        dtype=str,
+    )
+    metadata_sheet = pd.DataFrame(
+        [
+            ["processed_at", strftime("%Y-%m-%d %H:%M:%S", localtime())],
+            ["template", str(template)],
+            ["files_count", str(len(outputs_namespace.OUTPUT_SET))],
+        ],
+        columns=["key", "value"],
+    )
+    xlsx_path = outputs_namespace.xlsx_path
+    logger.info(f"Saving results workbook to '{xlsx_path}'")
+
+    def write_workbook(temp_path):
+        with pd.ExcelWriter(temp_path) as writer:
+            results_sheet.to_excel(writer, sheet_name="Results", index=False)
+            metadata_sheet.to_excel(writer, sheet_name="Metadata", index=False)
+
+    write_atomically(xlsx_path, write_workbook)
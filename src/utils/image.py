@@ -6,14 +6,34 @@
  Github: https://github.com/Udayraj123
 
 """
+import base64
+import threading
+from functools import lru_cache
+from time import sleep
+
 import cv2
 import matplotlib.pyplot as plt
 import numpy as np
+from PIL import Image, ImageOps
 
+from src.exceptions import ImageReadError, ImageReadTimeoutError
 from src.logger import logger
 
 plt.rcParams["figure.figsize"] = (10.0, 8.0)
 CLAHE_HELPER = cv2.createCLAHE(clipLimit=5.0, tileGridSize=(8, 8))
+# Color models that are converted to RGB before taking the grayscale intensities
+COLOR_MODELS_TO_NORMALIZE = ("CMYK", "P", "PA")
+# From fastest to smoothest. 'nearest' drops pixels when downscaling, which can
+# thin out faint marks, while 'area' and 'lanczos' keep them at a higher cost
+RESIZE_INTERPOLATIONS = {
+    "nearest": cv2.INTER_NEAREST,
+    "linear": cv2.INTER_LINEAR,
+    "area": cv2.INTER_AREA,
+    "cubic": cv2.INTER_CUBIC,
+    "lanczos": cv2.INTER_LANCZOS4,
+}
+# Wait before the first retry of a failed image read, doubled on each retry
+READ_RETRY_BACKOFF_SECONDS = 0.5
 
 
 class ImageUtils:
@@ -25,11 +45,119 @@ class ImageUtils:
         cv2.imwrite(path, final_marked)
 
     @staticmethod
-    def resize_util(img, u_width, u_height=None):
+    def get_color_model(path):
+        try:
+            # Only the header is read here
+            with Image.open(path) as image:
+                return image.mode
+        except OSError:
+            return None
+
+    @staticmethod
+    def read_image_grayscale(path, preserve_bit_depth=False):
+        if ImageUtils.get_color_model(path) in COLOR_MODELS_TO_NORMALIZE:
+            # CMYK scans and palette images give wrong intensities otherwise
+            with Image.open(path) as image:
+                rgb_image = ImageOps.exif_transpose(image).convert("RGB")
+            return cv2.cvtColor(np.array(rgb_image), cv2.COLOR_RGB2GRAY)
+
+        if not preserve_bit_depth:
+            return cv2.imread(path, cv2.IMREAD_GRAYSCALE)
+
+        image = cv2.imread(path, cv2.IMREAD_GRAYSCALE | cv2.IMREAD_ANYDEPTH)
+        if image is not None and image.dtype != np.uint8:
+            # Stretch the used intensity range instead of dropping the low bits,
+            # so that faint marks within a narrow band of a 16-bit scan stay apart
+            image = cv2.normalize(
+                image, None, 0, 255, norm_type=cv2.NORM_MINMAX, dtype=cv2.CV_8U
+            )
+        return image
+
+    @staticmethod
+    def read_image_file(path, preserve_bit_depth=False):
+        # Reading the bytes first raises the IO errors, which the decoder
+        # would otherwise report as an undecodable file
+        with open(path, "rb") as f:
+            f.read()
+        return ImageUtils.read_image_grayscale(path, preserve_bit_depth)
+
+    @staticmethod
+    def read_image_file_with_timeout(path, preserve_bit_depth, timeout_seconds):
+        outcome = {}
+
+        def read():
+            try:
+                outcome["image"] = ImageUtils.read_image_file(path, preserve_bit_depth)
+            except Exception as error:
+                outcome["error"] = error
+
+        # A daemon thread, a read stuck on a stalled share must not block the exit
+        worker = threading.Thread(target=read, daemon=True)
+        worker.start()
+        worker.join(timeout_seconds)
+        if worker.is_alive():
+            raise ImageReadTimeoutError(
+                f"Reading '{path}' did not finish within {timeout_seconds} seconds"
+            )
+        if "error" in outcome:
+            raise outcome["error"]
+        return outcome["image"]
+
+    @staticmethod
+    def read_image_with_retry(
+        path, preserve_bit_depth=False, timeout_seconds=30, retries=2
+    ):
+        """
+        Reads an image on a worker thread with a timeout. IO errors are retried
+            with a backoff, whereas an undecodable file gives None without retries.
+        """
+        for attempt in range(retries + 1):
+            try:
+                return ImageUtils.read_image_file_with_timeout(
+                    path, preserve_bit_depth, timeout_seconds
+                )
+            except FileNotFoundError:
+                raise
+            except OSError as error:
+                if attempt == retries:
+                    raise ImageReadError(
+                        f"Unable to read '{path}' after {retries + 1} attempts: {error}"
+                    ) from error
+                backoff_seconds = READ_RETRY_BACKOFF_SECONDS * 2**attempt
+                logger.warning(
+                    f"Retrying '{path}' in {backoff_seconds} seconds after: {error}"
+                )
+                sleep(backoff_seconds)
+
+    @staticmethod
+    def get_difference_hash(image, hash_size=8):
+        # Each bit tells whether a pixel is brighter than its right neighbour,
+        # which survives re-compression and small exposure changes
+        resized = cv2.resize(
+            image, (hash_size + 1, hash_size), interpolation=cv2.INTER_AREA
+        )
+        bits = (resized[:, 1:] > resized[:, :-1]).flatten()
+        return int("".join("1" if bit else "0" for bit in bits), 2)
+
+    @staticmethod
+    def get_blur_score(image):
+        # Variance of the Laplacian, a blurred scan has few sharp edges
+        return round(float(cv2.Laplacian(image, cv2.CV_64F).var()), 2)
+
+    @staticmethod
+    def get_contrast_score(image):
+        return round(float(image.std()), 2)
+
+    @staticmethod
+    def resize_util(img, u_width, u_height=None, interpolation="linear"):
         if u_height is None:
             h, w = img.shape[:2]
             u_height = int(h * u_width / w)
-        return cv2.resize(img, (int(u_width), int(u_height)))
+        return cv2.resize(
+            img,
+            (int(u_width), int(u_height)),
+            interpolation=RESIZE_INTERPOLATIONS[interpolation],
+        )
 
     @staticmethod
     def resize_util_h(img, u_height, u_width=None):
@@ -38,6 +166,19 @@ class ImageUtils:
             u_width = int(w * u_height / h)
         return cv2.resize(img, (int(u_width), int(u_height)))
 
+    @staticmethod
+    def get_thumbnail_base64(image, max_dim):
+        # A small JPEG of the image, for embedding into the JSON results
+        height, width = image.shape[:2]
+        scale = min(1, max_dim / max(height, width))
+        thumbnail = cv2.resize(
+            image,
+            (max(1, round(width * scale)), max(1, round(height * scale))),
+            interpolation=cv2.INTER_AREA,
+        )
+        _, jpeg = cv2.imencode(".jpg", thumbnail, [cv2.IMWRITE_JPEG_QUALITY, 80])
+        return base64.b64encode(jpeg.tobytes()).decode("ascii")
+
     @staticmethod
     def grab_contours(cnts):
         # source: imutils package
@@ -86,16 +227,53 @@ class ImageUtils:
         return edged
 
     @staticmethod
-    def adjust_gamma(image, gamma=1.0):
+    @lru_cache(maxsize=None)
+    def get_gamma_table(gamma):
         # build a lookup table mapping the pixel values [0, 255] to
-        # their adjusted gamma values
+        # their adjusted gamma values, once per gamma across the batch
         inv_gamma = 1.0 / gamma
         table = np.array(
             [((i / 255.0) ** inv_gamma) * 255 for i in np.arange(0, 256)]
         ).astype("uint8")
+        # The cached table is shared by every call
+        table.setflags(write=False)
+        return table
 
+    @staticmethod
+    def adjust_gamma(image, gamma=1.0):
         # apply gamma correction using the lookup table
-        return cv2.LUT(image, table)
+        return cv2.LUT(image, ImageUtils.get_gamma_table(gamma))
+
+    @staticmethod
+    def decode_qr_code(image):
+        data, _, _ = cv2.QRCodeDetector().detectAndDecode(image)
+        return data if len(data) > 0 else None
+
+    @staticmethod
+    def deskew(image, max_skew=10):
+        """
+        Straightens a slightly rotated scan using the dominant angle of its
+            near-horizontal lines, returns the deskewed image and the skew angle
+            (in degrees, counter-clockwise) that was corrected.
+        """
+        edges = ImageUtils.auto_canny(image)
+        lines = cv2.HoughLines(edges, 1, np.pi / 1800, image.shape[1] // 3)
+        if lines is None:
+            return image, 0.0
+
+        # A horizontal line has its normal at 90 degrees
+        skew_angles = [90 - np.degrees(theta) for [[_, theta]] in lines]
+        skew_angles = [angle for angle in skew_angles if abs(angle) <= max_skew]
+        if len(skew_angles) == 0:
+            return image, 0.0
+
+        skew_angle = float(np.median(skew_angles))
+        h, w = image.shape[:2]
+        rotation_matrix = cv2.getRotationMatrix2D((w / 2, h / 2), -skew_angle, 1.0)
+        deskewed = cv2.warpAffine(
+            image, rotation_matrix, (w, h), borderMode=cv2.BORDER_REPLICATE
+        )
+        return deskewed, skew_angle
 
     @staticmethod
     def four_point_transform(image, pts):
@@ -88,6 +88,7 @@ class Stats:
     # veryBadPoints = []
     files_moved = 0
     files_not_moved = 0
+    fields_corrected = 0
 
 
 def wait_q():
@@ -103,5 +104,5 @@ def is_window_available(name: str) -> bool:
         cv2.getWindowProperty(name, cv2.WND_PROP_VISIBLE)
         return True
     except Exception as e:
-        print(e)
+        logger.debug(e)
         return False
@@ -0,0 +1,207 @@
+"""
+
+ OMRChecker
+
+ Author: Udayraj Deshmukh
+ Github: https://github.com/Udayraj123
+
+"""
+from statistics import median
+
+import cv2
+import numpy as np
+
+from src.exceptions import OMRCheckerError
+from src.utils.image import ImageUtils
+
+MAX_SIZE_DEVIATION = 0.5
+# Columns further apart than this factor of the bubbles gap start a new field block
+BLOCK_GAP_FACTOR = 1.5
+
+
+def is_likely_bubble(contour, max_bubble_size, auto_layout_params):
+    _, _, w, h = cv2.boundingRect(contour)
+    if not auto_layout_params.min_bubble_size <= min(w, h) <= max(w, h):
+        return False
+    if max(w, h) > max_bubble_size:
+        return False
+    # Rejects lines and text blocks
+    if abs(w / h - 1) > auto_layout_params.max_aspect_deviation:
+        return False
+    # The area enclosed by a round contour is about pi/4 of its bounding box,
+    # whereas a square stray mark fills its bounding box completely
+    fill_ratio = cv2.contourArea(contour) / ((w - 1) * (h - 1))
+    return (
+        auto_layout_params.min_fill_ratio
+        <= fill_ratio
+        <= auto_layout_params.max_fill_ratio
+    )
+
+
+def find_bubble_regions(image, auto_layout_params):
+    # Bubbles are dark outlines (or fills) on a light page
+    _, thresholded = cv2.threshold(
+        image, 0, 255, cv2.THRESH_BINARY_INV | cv2.THRESH_OTSU
+    )
+    contours = ImageUtils.grab_contours(
+        cv2.findContours(thresholded, cv2.RETR_EXTERNAL, cv2.CHAIN_APPROX_SIMPLE)
+    )
+    max_bubble_size = image.shape[1] // 10
+    regions = [
+        cv2.boundingRect(contour)
+        for contour in contours
+        if is_likely_bubble(contour, max_bubble_size, auto_layout_params)
+    ]
+
+    if len(regions) == 0:
+        return regions
+
+    # Drop shapes (text, marks) that are much smaller or larger than a typical bubble
+    typical_size = median(max(w, h) for _, _, w, h in regions)
+    return [
+        region
+        for region in regions
+        if abs(max(region[2:]) / typical_size - 1) <= MAX_SIZE_DEVIATION
+    ]
+
+
+def cluster_positions(positions, max_gap):
+    clusters = []
+    for position in sorted(positions):
+        if clusters and position - clusters[-1][-1] <= max_gap:
+            clusters[-1].append(position)
+        else:
+            clusters.append([position])
+    return [sum(cluster) / len(cluster) for cluster in clusters]
+
+
+def get_gap(centers, fallback):
+    if len(centers) < 2:
+        return fallback
+    return round(median(b - a for a, b in zip(centers, centers[1:])))
+
+
+def split_into_blocks(column_centers):
+    if len(column_centers) < 2:
+        return [column_centers]
+    bubbles_gap = min(b - a for a, b in zip(column_centers, column_centers[1:]))
+    blocks = [[column_centers[0]]]
+    for previous, center in zip(column_centers, column_centers[1:]):
+        if center - previous > BLOCK_GAP_FACTOR * bubbles_gap:
+            blocks.append([])
+        blocks[-1].append(center)
+    return blocks
+
+
+def render_template_to_image(template, marks, ink_color=40):
+    """
+    Draws a synthetic sheet of the template with every bubble outlined,
+        marks: field label -> bubble value to fill in for that field
+    """
+    page_width, page_height = template.page_dimensions
+    image = np.full((page_height, page_width), 255, dtype=np.uint8)
+    for field_block in template.field_blocks:
+        bubble_width, bubble_height = field_block.bubble_dimensions
+        for field_bubbles in field_block.traverse_bubbles:
+            for bubble in field_bubbles:
+                is_marked = marks.get(bubble.field_label) == bubble.field_value
+                cv2.rectangle(
+                    image,
+                    (bubble.x, bubble.y),
+                    (bubble.x + bubble_width - 1, bubble.y + bubble_height - 1),
+                    ink_color,
+                    -1 if is_marked else 2,
+                )
+    return image
+
+
+def get_bubble_mark_counts(template, marked_bubbles_of_files):
+    """
+    Counts how often each bubble was marked across the processed files,
+        marked_bubbles_of_files: field label -> marked values, one dict per file
+    """
+    mark_counts = {}
+    for field_block in template.field_blocks:
+        for field_bubbles in field_block.traverse_bubbles:
+            for bubble in field_bubbles:
+                mark_counts.setdefault(bubble.field_label, {})[bubble.field_value] = 0
+    for marked_bubbles in marked_bubbles_of_files:
+        for field_label, field_values in marked_bubbles.items():
+            for field_value in field_values:
+                mark_counts[field_label][field_value] += 1
+    return mark_counts
+
+
+def render_mark_heatmap(template, mark_counts, files_count):
+    # Bubbles colored by their mark frequency, from blue (never) to red (always).
+    # A misaligned template shows up as marks smeared onto the neighbouring bubbles
+    page_width, page_height = template.page_dimensions
+    heatmap = np.full((page_height, page_width, 3), 255, dtype=np.uint8)
+    for field_block in template.field_blocks:
+        bubble_width, bubble_height = field_block.bubble_dimensions
+        for field_bubbles in field_block.traverse_bubbles:
+            for bubble in field_bubbles:
+                mark_count = mark_counts[bubble.field_label][bubble.field_value]
+                frequency = mark_count / max(files_count, 1)
+                color = cv2.applyColorMap(
+                    np.uint8([[round(255 * frequency)]]), cv2.COLORMAP_JET
+                )[0, 0]
+                cv2.rectangle(
+                    heatmap,
+                    (bubble.x, bubble.y),
+                    (bubble.x + bubble_width - 1, bubble.y + bubble_height - 1),
+                    color.tolist(),
+                    -1,
+                )
+    return heatmap
+
+
+def generate_draft_template(image, auto_layout_params):
+    regions = find_bubble_regions(image, auto_layout_params)
+    if len(regions) == 0:
+        raise OMRCheckerError(
+            "Could not detect any bubbles to generate a draft template"
+        )
+
+    bubble_width = round(median(w for _, _, w, _ in regions))
+    bubble_height = round(median(h for _, _, _, h in regions))
+    x_centers = [x + w / 2 for x, _, w, _ in regions]
+    column_centers = cluster_positions(x_centers, bubble_width / 2)
+
+    field_blocks = {}
+    next_label = 1
+    for block_index, block_columns in enumerate(split_into_blocks(column_centers)):
+        block_start_x = block_columns[0] - bubble_width / 2
+        block_end_x = block_columns[-1] + bubble_width / 2
+        y_centers = [
+            y + h / 2
+            for x, y, w, h in regions
+            if block_start_x <= x + w / 2 <= block_end_x
+        ]
+        row_centers = cluster_positions(y_centers, bubble_height / 2)
+        last_label = next_label + len(row_centers) - 1
+        field_labels = (
+            f"q{next_label}..{last_label}"
+            if last_label > next_label
+            else f"q{next_label}"
+        )
+        field_blocks[f"MCQ_Block_{block_index + 1}"] = {
+            "bubbleValues": [chr(ord("A") + i) for i in range(len(block_columns))],
+            "direction": "horizontal",
+            "origin": [
+                max(0, round(block_start_x)),
+                max(0, round(row_centers[0] - bubble_height / 2)),
+            ],
+            "fieldLabels": [field_labels],
+            "bubblesGap": get_gap(block_columns, 2 * bubble_width),
+            "labelsGap": get_gap(row_centers, 2 * bubble_height),
+        }
+        next_label = last_label + 1
+
+    page_height, page_width = image.shape[:2]
+    return {
+        "pageDimensions": [page_width, page_height],
+        "bubbleDimensions": [bubble_width, bubble_height],
+        "preProcessors": [],
+        "fieldBlocks": field_blocks,
+    }
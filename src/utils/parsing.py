@@ -1,12 +1,16 @@
 import re
 from copy import deepcopy
+from datetime import date
 from fractions import Fraction
+from pathlib import Path
 
 from deepmerge import Merger
 from dotmap import DotMap
 
-from src.constants import FIELD_LABEL_NUMBER_REGEX
+from src.constants import FIELD_LABEL_NUMBER_REGEX, TEMPLATE_SCHEMA_VERSION
 from src.defaults import CONFIG_DEFAULTS, TEMPLATE_DEFAULTS
+from src.exceptions import OMRCheckerError, TemplateInvalidError
+from src.logger import logger
 from src.schemas.constants import FIELD_STRING_REGEX_GROUPS
 from src.utils.file import load_json
 from src.utils.validations import (
@@ -39,12 +43,40 @@ def get_concatenated_response(omr_response, template):
         custom_label = "".join([omr_response[k] for k in concatenate_keys])
         concatenated_response[field_label] = custom_label
 
+    for field_label, date_segments in template.date_labels.items():
+        day, month, year = get_date_segments(omr_response, date_segments)
+        # An impossible date, e.g. month 13, keeps its bubbled digits for review
+        concatenated_response[field_label] = (
+            assemble_date(day, month, year) or f"{day}/{month}/{year}"
+        )
+
     for field_label in template.non_custom_labels:
         concatenated_response[field_label] = omr_response[field_label]
 
     return concatenated_response
 
 
+def get_date_segments(omr_response, date_segments):
+    # The bubbled digits of the day, month and year
+    return ["".join(omr_response[k] for k in keys) for keys in date_segments]
+
+
+def assemble_date(day, month, year):
+    # An ISO date string, or None for a blank or impossible date
+    try:
+        return date(int(year), int(month), int(day)).isoformat()
+    except ValueError:
+        return None
+
+
+def get_invalid_dates(omr_response, template):
+    return [
+        field_label
+        for field_label, date_segments in template.date_labels.items()
+        if assemble_date(*get_date_segments(omr_response, date_segments)) is None
+    ]
+
+
 def open_config_with_defaults(config_path):
     user_tuning_config = load_json(config_path)
     user_tuning_config = OVERRIDE_MERGER.merge(
@@ -55,8 +87,67 @@ def open_config_with_defaults(config_path):
     return DotMap(user_tuning_config, _dynamic=False)
 
 
-def open_template_with_defaults(template_path):
+def load_template_with_parents(template_path, visited_paths=()):
+    template_path = Path(template_path).resolve()
+    if template_path in visited_paths:
+        chain = " -> ".join(str(path) for path in visited_paths + (template_path,))
+        raise TemplateInvalidError(
+            f"Circular 'extends' chain found in template: {chain}"
+        )
+
     user_template = load_json(template_path)
+    parent_path = user_template.pop("extends", None)
+    if parent_path is None:
+        return user_template
+
+    # The parent path is resolved relative to the extending template
+    parent_template = load_template_with_parents(
+        template_path.parent.joinpath(parent_path),
+        visited_paths + (template_path,),
+    )
+    merged_template = {**parent_template, **user_template}
+    # Same-named entries of the child replace the parent's entries as a whole
+    for key in ["fieldBlocks", "customLabels"]:
+        merged_template[key] = {
+            **parent_template.get(key, {}),
+            **user_template.get(key, {}),
+        }
+    return merged_template
+
+
+def migrate_template_v1(user_template):
+    # Version 1 templates predate the referenceStrips and valueMap keys
+    return {
+        "referenceStrips": [],
+        "valueMap": {},
+        **user_template,
+        "schemaVersion": 2,
+    }
+
+
+# The upgrade step of each older schema version to the next one
+TEMPLATE_MIGRATIONS = {1: migrate_template_v1}
+
+
+def migrate_template(user_template, template_path):
+    version = user_template.get("schemaVersion", 1)
+    if not isinstance(version, int) or version > TEMPLATE_SCHEMA_VERSION:
+        raise TemplateInvalidError(
+            f"Unsupported schemaVersion {version} in template '{template_path}', this version of OMRChecker reads templates up to version {TEMPLATE_SCHEMA_VERSION}"
+        )
+    if version < TEMPLATE_SCHEMA_VERSION:
+        logger.info(
+            f"Migrating template '{template_path}' from schema version {version} to {TEMPLATE_SCHEMA_VERSION}"
+        )
+    while version < TEMPLATE_SCHEMA_VERSION:
+        user_template = TEMPLATE_MIGRATIONS[version](user_template)
+        version = user_template["schemaVersion"]
+    return user_template
+
+
+def open_template_with_defaults(template_path):
+    user_template = load_template_with_parents(template_path)
+    user_template = migrate_template(user_template, template_path)
     user_template = OVERRIDE_MERGER.merge(deepcopy(TEMPLATE_DEFAULTS), user_template)
     validate_template_json(user_template, template_path)
     return user_template
@@ -68,6 +159,34 @@ def open_evaluation_with_validation(evaluation_path):
     return user_evaluation_config
 
 
+def open_corrections_with_validation(corrections_path):
+    corrections = load_json(corrections_path)
+    is_valid = isinstance(corrections, dict) and all(
+        isinstance(file_corrections, dict)
+        and all(isinstance(value, str) for value in file_corrections.values())
+        for file_corrections in corrections.values()
+    )
+    if not is_valid:
+        raise OMRCheckerError(
+            f"Corrections file '{corrections_path}' must map each file name to an object of field labels and their corrected string values"
+        )
+    return corrections
+
+
+def apply_corrections(omr_response, file_corrections, file_id):
+    # Overrides the detected values, returns the labels of the corrected fields
+    corrected_fields = []
+    for field_label, value in file_corrections.items():
+        if field_label not in omr_response:
+            logger.warning(
+                f"Ignoring the correction of unknown field '{field_label}' for '{file_id}'"
+            )
+            continue
+        omr_response[field_label] = value
+        corrected_fields.append(field_label)
+    return corrected_fields
+
+
 def parse_fields(key, fields):
     parsed_fields = []
     fields_set = set()
@@ -75,7 +194,7 @@ def parse_fields(key, fields):
         fields_array = parse_field_string(field_string)
         current_set = set(fields_array)
         if not fields_set.isdisjoint(current_set):
-            raise Exception(
+            raise OMRCheckerError(
                 f"Given field string '{field_string}' has overlapping field(s) with other fields in '{key}': {fields}"
             )
         fields_set.update(current_set)
@@ -90,7 +209,7 @@ def parse_field_string(field_string):
         ]
         start, end = int(start), int(end)
         if start >= end:
-            raise Exception(
+            raise OMRCheckerError(
                 f"Invalid range in fields string: '{field_string}', start: {start} is not less than end: {end}"
             )
         return [
@@ -100,6 +219,60 @@ def parse_field_string(field_string):
         return [field_string]
 
 
+def shift_field_string(field_string, offset):
+    # Shifts the numbers of a field string, e.g. 'q1..25' by 25 gives 'q26..50'
+    if "." in field_string:
+        field_prefix, start, end = re.findall(FIELD_STRING_REGEX_GROUPS, field_string)[
+            0
+        ]
+        return f"{field_prefix}{int(start) + offset}..{int(end) + offset}"
+    label_prefix, label_suffix = re.findall(FIELD_LABEL_NUMBER_REGEX, field_string)[0]
+    if len(label_suffix) == 0:
+        return field_string
+    return f"{label_prefix}{int(label_suffix) + offset}"
+
+
+def expand_columns(field_blocks_object, columns):
+    """
+    Repeats the field blocks in each column of a multi-column sheet. The block
+        origins are relative to the column origin and the field numbers continue
+        in the next column.
+    """
+    if len(columns) == 0:
+        return field_blocks_object
+    fields_per_column = sum(
+        len(parse_fields(f"Field Block: {block_name}", block["fieldLabels"]))
+        for block_name, block in field_blocks_object.items()
+    )
+    expanded_field_blocks = {}
+    for column_index, column in enumerate(columns):
+        column_x, column_y = column["origin"]
+        offset = column_index * fields_per_column
+        for block_name, block in field_blocks_object.items():
+            block_x, block_y = block["origin"]
+            expanded_field_blocks[f"{block_name}_col{column_index + 1}"] = {
+                **block,
+                "origin": [column_x + block_x, column_y + block_y],
+                "fieldLabels": [
+                    shift_field_string(field_string, offset)
+                    for field_string in block["fieldLabels"]
+                ],
+            }
+    return expanded_field_blocks
+
+
+def offset_field_blocks(field_blocks_object, origin):
+    # Moves the field blocks into a student region, their origins are relative to it
+    origin_x, origin_y = origin
+    return {
+        block_name: {
+            **block,
+            "origin": [origin_x + block["origin"][0], origin_y + block["origin"][1]],
+        }
+        for block_name, block in field_blocks_object.items()
+    }
+
+
 def custom_sort_output_columns(field_label):
     label_prefix, label_suffix = re.findall(FIELD_LABEL_NUMBER_REGEX, field_label)[0]
     return [label_prefix, int(label_suffix) if len(label_suffix) > 0 else 0]
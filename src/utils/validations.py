@@ -12,6 +12,11 @@ import jsonschema
 from jsonschema import validate
 from rich.table import Table
 
+from src.exceptions import (
+    ConfigInvalidError,
+    EvaluationInvalidError,
+    TemplateInvalidError,
+)
 from src.logger import console, logger
 from src.schemas import SCHEMA_JSONS, SCHEMA_VALIDATORS
 
@@ -40,7 +45,7 @@ def validate_evaluation_json(json_data, evaluation_path):
             else:
                 table.add_row(key, msg)
         console.print(table, justify="center")
-        raise Exception(
+        raise EvaluationInvalidError(
             f"Provided Evaluation JSON is Invalid: '{evaluation_path}'"
         ) from None
 
@@ -75,7 +80,7 @@ def validate_template_json(json_data, template_path):
             else:
                 table.add_row(key, msg)
         console.print(table, justify="center")
-        raise Exception(
+        raise TemplateInvalidError(
             f"Provided Template JSON is Invalid: '{template_path}'"
         ) from None
 
@@ -104,7 +109,9 @@ def validate_config_json(json_data, config_path):
             else:
                 table.add_row(key, msg)
         console.print(table, justify="center")
-        raise Exception(f"Provided config JSON is Invalid: '{config_path}'") from None
+        raise ConfigInvalidError(
+            f"Provided config JSON is Invalid: '{config_path}'"
+        ) from None
 
 
 def parse_validation_error(error):